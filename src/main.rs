@@ -1,9 +1,13 @@
 use macroquad::prelude::*;
-use ::rand::{thread_rng, Rng};
+use ::rand::{seq::SliceRandom, thread_rng};
 use std::cmp::{min, max};
 
-use std::collections::HashMap;
-use std::io::Cursor;
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{Cursor, ErrorKind, Write};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
 use rodio::source::Source;
@@ -15,28 +19,119 @@ const MUSIC_A: &[u8] = include_bytes!("../resources/music/music-a.mp3");
 const MUSIC_B: &[u8] = include_bytes!("../resources/music/music-b.mp3");
 
 const MUSIC_LIST: [&[u8]; 3] = [MUSIC_A_GB, MUSIC_A, MUSIC_B];
+const MUSIC_VOLUME: f32 = 0.5;    // Default; adjustable in steps of MUSIC_VOLUME_STEP.
+const MUSIC_VOLUME_STEP: f32 = 0.1;
+const COLOR_CHANNEL_STEP: f32 = 0.1; // Piece color rows move a channel this much.
+const MUSIC_DUCK_FACTOR: f32 = 0.6; // Share of the volume kept while ducked.
+const MUSIC_MAX_SPEED: f32 = 1.25;   // Playback speed with the stack at the top.
 
 // -------------------------------------------------------------------
 // Game constants
 const GRID_WIDTH: usize = 10;
-const GRID_HEIGHT: usize = 20;
+// The logical board carries hidden buffer rows above the visible 10x20
+// field; pieces spawn there and those rows are never drawn.
+const GRID_HEIGHT: usize = 22;
+const HIDDEN_ROWS: usize = 2;
+const VISIBLE_ROWS: usize = GRID_HEIGHT - HIDDEN_ROWS;
+const DANGER_ROWS: usize = 3; // Visible rows that trigger the top-out warning.
 const TILE_SIZE: f32 = 30.0;
 const PREVIEW_TILE_SIZE: f32 = 25.0;
+const QUEUE_TILE_SIZE: f32 = 18.0;
+const COMPACT_TILE_SIZE: f32 = 14.0; // Hold/Next previews in the compact layout.
+
+// Upcoming pieces kept in the queue, and the most the HUD will show.
+const NEXT_QUEUE_LEN: usize = 5;
 
 const FALL_SPEED: f32 = 3.0;
+const ZEN_FALL_SPEED: f32 = 1.0;
+const ULTRA_DURATION: f32 = 180.0;
+const LOW_TIME_WARNING: f32 = 10.0;
 const SOFT_DROP_SPEED: f32 = 15.0;
 const INITIAL_HORIZONTAL_DELAY: f32 = 0.2;
 const HORIZONTAL_REPEAT_DELAY: f32 = 0.1;
 
 const GAME_AREA_COLOR: Color = Color::new(0.2, 0.2, 0.2, 1.0);
+const GRID_LINE_COLOR: Color = Color::new(0.28, 0.28, 0.28, 1.0);
 const BLACK_COLOR: Color = BLACK;
 const GOLD_COLOR: Color = Color::new(1.0, 0.84, 0.0, 1.0);
 const SILVER_COLOR: Color = Color::new(0.75, 0.75, 0.75, 1.0);
 
+const DROP_TRAIL_DURATION: f32 = 0.2;
+const LOCK_FLASH_DURATION: f32 = 0.1;
+const GAME_OVER_ANIM_DURATION: f32 = 1.5;
+const SQUARE_FREEZE_MAX: f32 = 4.0; // Longest play is held for a blinking bonus square.
+const ROOKIE_WAIT_TIMEOUT: f32 = 3.0; // Rookie assist: gravity resumes after this long without input.
+const POPUP_DURATION: f32 = 1.5;
+const HOLD_TIME_COST: f32 = 2.0; // Ultra modifier: seconds off the clock per hold.
+const LATENCY_SAMPLES: usize = 100;     // Latency test keeps this many recent presses.
+const LATENCY_BUCKET_MS: f64 = 2.0;     // Histogram bar width.
+const LATENCY_BUCKETS: usize = 20;      // Bars before the overflow bar.
+const BIG_SCALE: i32 = 2; // Big mode: board cells per side of one piece cell.
+const TGM_HISTORY_LEN: usize = 4; // Recent pieces the TGM randomizer tries to avoid.
+const TGM_REROLLS: usize = 4;     // Extra draws it makes before accepting a repeat.
+const SECOND_CHANCE_ROWS: usize = 6;        // Visible rows cleared by a second chance.
+const SECOND_CHANCE_PENALTY: u32 = 1000;    // Score given up for a second chance.
+const INVISIBLE_DELAY: f64 = 1.0; // Seconds a locked piece stays visible in invisible mode.
+const CURTAIN_COLOR: Color = Color::new(0.45, 0.45, 0.45, 1.0);
+
 const GOLD_POINTS: u32 = 500;
 const SILVER_POINTS: u32 = 200;
 
-const NES_COLORS: [Color; 7] = [
+// Choices offered for the per-game hold limit (None = unlimited).
+const HOLD_LIMIT_OPTIONS: [Option<u32>; 5] = [None, Some(1), Some(3), Some(5), Some(10)];
+
+// The NES palette of the seven tetrominoes as editable RGB triples.
+fn nes_piece_colors() -> [[f32; 3]; TETROMINOES.len()] {
+    TETROMINOES.map(|t| {
+        let c = NES_COLORS[t as usize];
+        [c.r, c.g, c.b]
+    })
+}
+
+// Which RGB channel a piece color row edits.
+fn color_channel(item: MenuItem) -> usize {
+    match item {
+        MenuItem::ColorGreen => 1,
+        MenuItem::ColorBlue => 2,
+        _ => 0,
+    }
+}
+
+// Menu names for the spawn rotation setting, indexed by clockwise quarter turns.
+const SPAWN_ROTATION_NAMES: [&str; 4] = ["Default", "1 Turn CW", "2 Turns", "1 Turn CCW"];
+
+// Most single-row shifts tried to make room for a Zen spawn; the board
+// is empty after this many, so the spawn always fits by then.
+const MAX_STACK_SHIFTS: usize = GRID_HEIGHT;
+
+// Finished games are appended here when the game log is on. Columns, in
+// order: timestamp (Unix seconds), mode, score, lines, play_time (seconds),
+// then one count per piece type: the seven tetrominoes followed by the
+// twelve pentominoes.
+const GAME_LOG_PATH: &str = "games.csv";
+
+// Menu and overlay choices are remembered here between runs, one
+// `key=value` per line. See GameState::settings_text for the keys.
+const SETTINGS_PATH: &str = "settings.txt";
+
+// Key bindings shown in the HUD controls text and the help overlay.
+const CONTROLS: [(&str, &str); 13] = [
+    ("Left/Right", "Move"),
+    ("Up", "Hard Drop"),
+    ("Down", "Soft Drop"),
+    ("Z/X", "Rotate"),
+    ("C", "Hold"),
+    ("Enter", "Pause"),
+    ("Space", "Start"),
+    ("N", "Change Song"),
+    ("M", "Mute Music"),
+    ("O", "Settings"),
+    ("H", "Show Controls"),
+    ("F2", "Compact Layout"),
+    ("Up/Down, Left/Right", "Menu Options"),
+];
+
+const NES_COLORS: [Color; 19] = [
     Color { r: 0.0,    g: 1.0,    b: 1.0,    a: 1.0 }, // I
     Color { r: 1.0,    g: 1.0,    b: 0.0,    a: 1.0 }, // O
     Color { r: 0.6667, g: 0.0,    b: 1.0,    a: 1.0 }, // T
@@ -44,30 +139,55 @@ const NES_COLORS: [Color; 7] = [
     Color { r: 1.0,    g: 0.0,    b: 0.0,    a: 1.0 }, // Z
     Color { r: 0.0,    g: 0.0,    b: 1.0,    a: 1.0 }, // J
     Color { r: 1.0,    g: 0.3334, b: 0.0,    a: 1.0 }, // L
+    Color { r: 1.0,    g: 0.4,    b: 0.7,    a: 1.0 }, // F5
+    Color { r: 0.5,    g: 1.0,    b: 1.0,    a: 1.0 }, // I5
+    Color { r: 1.0,    g: 0.6,    b: 0.2,    a: 1.0 }, // L5
+    Color { r: 0.6,    g: 0.8,    b: 0.2,    a: 1.0 }, // N5
+    Color { r: 1.0,    g: 0.85,   b: 0.5,    a: 1.0 }, // P5
+    Color { r: 0.8,    g: 0.5,    b: 1.0,    a: 1.0 }, // T5
+    Color { r: 0.2,    g: 0.6,    b: 1.0,    a: 1.0 }, // U5
+    Color { r: 0.0,    g: 0.6,    b: 0.6,    a: 1.0 }, // V5
+    Color { r: 0.9,    g: 0.2,    b: 0.5,    a: 1.0 }, // W5
+    Color { r: 1.0,    g: 1.0,    b: 1.0,    a: 1.0 }, // X5
+    Color { r: 0.6,    g: 0.4,    b: 0.2,    a: 1.0 }, // Y5
+    Color { r: 0.7,    g: 0.0,    b: 0.0,    a: 1.0 }, // Z5
 ];
 
 // MusicManager modified to use embedded audio.
 #[allow(dead_code)]
 struct MusicManager {
-    mus_stream:OutputStream,
-    mus_stream_hndl:OutputStreamHandle,
+    mus_output:Option<(OutputStream, OutputStreamHandle)>, // None when playing silently.
     mus_sink:Sink,
     mus_track:u32,
     muted:bool,
-    paused:bool,
+    ducked:bool,
+    speed:f32,
+    volume:f32,
 }
 
 impl MusicManager {
     fn new() -> Self {
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
+        // Without an audio device the game still runs, just without music.
+        let Ok((stream, stream_handle)) = OutputStream::try_default() else { return Self::silent() };
+        let Ok(sink) = Sink::try_new(&stream_handle) else { return Self::silent() };
+        Self::with_sink(Some((stream, stream_handle)), sink)
+    }
+
+    // A manager with no output device. Its sink is never fed, so every
+    // control works but nothing is heard.
+    fn silent() -> Self {
+        Self::with_sink(None, Sink::new_idle().0)
+    }
+
+    fn with_sink(output: Option<(OutputStream, OutputStreamHandle)>, sink: Sink) -> Self {
         MusicManager {
-            mus_stream:stream,
-            mus_stream_hndl:stream_handle,
+            mus_output:output,
             mus_sink:sink,
             mus_track:0,
             muted:false,
-            paused:false,
+            ducked:false,
+            speed:1.0,
+            volume:MUSIC_VOLUME,
         }
     }
 
@@ -78,60 +198,452 @@ impl MusicManager {
         let track_index = (self.mus_track % MUSIC_LIST.len() as u32) as usize;
         let track_data = MUSIC_LIST[track_index];
         self.mus_track += 1;
-        // Create an in-memory cursor for the embedded audio data.
-        let cursor = Cursor::new(track_data);
-        // Decode the audio data and set it to repeat infinitely.
-        let source = Decoder::new(cursor).unwrap().repeat_infinite();
-        // Append the source into the sink and set volume.
-        self.mus_sink.append(source);
-        self.mus_sink.set_volume(0.5);
+        // An idle sink never drains, so a silent manager queues nothing.
+        if self.mus_output.is_some() {
+            // Create an in-memory cursor for the embedded audio data.
+            let cursor = Cursor::new(track_data);
+            // Decode the audio data and set it to repeat infinitely.
+            let source = Decoder::new(cursor).unwrap().repeat_infinite();
+            // Append the source into the sink.
+            self.mus_sink.append(source);
+        }
+        self.apply_volume();
         self.mus_sink.play();
     }
 
-    pub fn mute(&mut self){
-        if self.muted{
-            self.mus_sink.set_volume(0.5);
-        }
-        else{
-            self.mus_sink.set_volume(0.0);
+    pub fn set_muted(&mut self, muted: bool){
+        self.muted = muted;
+        self.apply_volume();
+    }
+
+    pub fn set_volume(&mut self, volume: f32){
+        self.volume = volume.clamp(0.0, 1.0);
+        self.apply_volume();
+    }
+
+    // Temporarily lowers the music without touching the mute setting.
+    pub fn set_ducked(&mut self, ducked: bool){
+        if self.ducked != ducked {
+            self.ducked = ducked;
+            self.apply_volume();
         }
-        self.muted = !self.muted;
     }
 
-    pub fn pause(&mut self){
-        if self.paused{
-            self.mus_sink.play();
+    fn apply_volume(&mut self){
+        let volume = if self.muted {
+            0.0
+        } else if self.ducked {
+            self.volume * MUSIC_DUCK_FACTOR
+        } else {
+            self.volume
+        };
+        self.mus_sink.set_volume(volume);
+    }
+
+    // Sets the pause state outright so it can't drift from the game's.
+    pub fn set_paused(&mut self, paused: bool){
+        if paused{
+            self.mus_sink.pause();
         }
         else{
-            self.mus_sink.pause();
+            self.mus_sink.play();
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: f32){
+        // Skip inaudible changes rather than touching the sink every frame,
+        // but always land exactly on normal speed.
+        if (self.speed - speed).abs() > 0.005 || (speed == 1.0 && self.speed != 1.0) {
+            self.speed = speed;
+            self.mus_sink.set_speed(speed);
         }
-        self.paused = !self.paused;
     }
 
     pub fn reset(&mut self){
         self.mus_sink.clear();
         self.mus_track = 0;
+        self.set_ducked(false);
+        self.speed = 1.0;
+        self.mus_sink.set_speed(1.0);
+    }
+}
+
+// Game modes selectable from the start screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GameMode {
+    Classic,
+    Zen,   // No top-out and slow fixed gravity.
+    Ultra, // Three minutes to score as much as possible.
+    Pento, // Classic rules with five-cell pentominoes.
+}
+
+impl GameMode {
+    const ALL: [GameMode; 4] = [GameMode::Classic, GameMode::Zen, GameMode::Ultra, GameMode::Pento];
+
+    fn name(self) -> &'static str {
+        match self {
+            GameMode::Classic => "Classic",
+            GameMode::Zen => "Zen",
+            GameMode::Ultra => "Ultra",
+            GameMode::Pento => "Pento",
+        }
+    }
+
+    // Pieces dealt in this mode.
+    fn piece_set(self) -> &'static [TetrominoType] {
+        match self {
+            GameMode::Pento => &PENTOMINOES,
+            _ => &TETROMINOES,
+        }
+    }
+
+    fn cycle(self, step: i32) -> Self {
+        let len = Self::ALL.len() as i32;
+        let index = Self::ALL.iter().position(|&m| m == self).unwrap() as i32;
+        Self::ALL[(index + step).rem_euclid(len) as usize]
+    }
+}
+
+// Why a game ended in a top-out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TopOut {
+    BlockOut, // The next piece collided at its spawn position.
+    LockOut,  // A piece locked entirely inside the hidden spawn rows.
+}
+
+impl TopOut {
+    fn name(self) -> &'static str {
+        match self {
+            TopOut::BlockOut => "Block Out",
+            TopOut::LockOut => "Lock Out",
+        }
+    }
+}
+
+// How individual blocks are rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlockStyle {
+    Bevel,   // Classic square SNES-style bevel.
+    Rounded, // Softer rounded corners.
+}
+
+impl BlockStyle {
+    const ALL: [BlockStyle; 2] = [BlockStyle::Bevel, BlockStyle::Rounded];
+
+    fn name(self) -> &'static str {
+        match self {
+            BlockStyle::Bevel => "Bevel",
+            BlockStyle::Rounded => "Rounded",
+        }
+    }
+
+    fn toggle(self) -> Self {
+        match self {
+            BlockStyle::Bevel => BlockStyle::Rounded,
+            BlockStyle::Rounded => BlockStyle::Bevel,
+        }
+    }
+}
+
+// HUD arrangement around the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    Full,    // Side panels with stats, Next/Hold and the controls text.
+    Compact, // Board in the top-left corner with Hold/Score above and Next below.
+}
+
+impl Layout {
+    const ALL: [Layout; 2] = [Layout::Full, Layout::Compact];
+
+    fn name(self) -> &'static str {
+        match self {
+            Layout::Full => "Full",
+            Layout::Compact => "Compact",
+        }
+    }
+
+    fn toggle(self) -> Self {
+        match self {
+            Layout::Full => Layout::Compact,
+            Layout::Compact => Layout::Full,
+        }
+    }
+
+    // Top-left corner of the board on screen.
+    fn board_origin(self, board_w: f32, board_h: f32) -> (f32, f32) {
+        match self {
+            Layout::Full => ((screen_width() - board_w) / 2.0, (screen_height() - board_h) / 2.0 - 50.0),
+            Layout::Compact => (20.0, 50.0),
+        }
+    }
+}
+
+// How upcoming pieces are chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Randomizer {
+    TrueRandom, // Every piece independently uniform.
+    SevenBag,   // Deal a shuffled copy of the piece set before refilling.
+    TgmHistory, // Reroll draws that match the last few pieces.
+}
+
+impl Randomizer {
+    const ALL: [Randomizer; 3] = [Randomizer::TrueRandom, Randomizer::SevenBag, Randomizer::TgmHistory];
+
+    fn name(self) -> &'static str {
+        match self {
+            Randomizer::TrueRandom => "Random",
+            Randomizer::SevenBag => "Bag",
+            Randomizer::TgmHistory => "TGM History",
+        }
+    }
+
+    fn cycle(self, step: i32) -> Self {
+        let len = Self::ALL.len() as i32;
+        let index = Self::ALL.iter().position(|&r| r == self).unwrap() as i32;
+        Self::ALL[(index + step).rem_euclid(len) as usize]
+    }
+}
+
+// Randomizer state carried between draws.
+struct PieceGenerator {
+    kind: Randomizer,
+    bag: Vec<TetrominoType>,
+    history: VecDeque<TetrominoType>,
+}
+
+impl PieceGenerator {
+    fn new(kind: Randomizer) -> Self {
+        PieceGenerator { kind, bag: Vec::new(), history: VecDeque::with_capacity(TGM_HISTORY_LEN) }
+    }
+
+    // Forgets the bag and history, e.g. for a new game.
+    fn reset(&mut self) {
+        self.bag.clear();
+        self.history.clear();
+    }
+
+    // Draws the next piece from `set`, the current mode's pieces. The bag
+    // holds one of each piece in `set`, so with pentominoes it deals twelve.
+    fn next_piece(&mut self, set: &[TetrominoType]) -> TetrominoType {
+        let mut rng = thread_rng();
+        match self.kind {
+            Randomizer::TrueRandom => *set.choose(&mut rng).unwrap(),
+            Randomizer::SevenBag => {
+                if self.bag.is_empty() {
+                    self.bag.extend_from_slice(set);
+                    self.bag.shuffle(&mut rng);
+                }
+                self.bag.pop().unwrap()
+            }
+            Randomizer::TgmHistory => {
+                let mut piece = *set.choose(&mut rng).unwrap();
+                for _ in 0..TGM_REROLLS {
+                    if !self.history.contains(&piece) {
+                        break;
+                    }
+                    piece = *set.choose(&mut rng).unwrap();
+                }
+                if self.history.len() == TGM_HISTORY_LEN {
+                    self.history.pop_front();
+                }
+                self.history.push_back(piece);
+                piece
+            }
+        }
     }
 }
 
+// Rows of the start-screen options menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MenuItem {
+    Mode,
+    Randomizer,
+    ReducedMotion,
+    Music,
+    MusicVolume,
+    LockOut,
+    Hold,
+    HoldLimit,
+    HoldCostsTime,
+    NextCount,
+    Invisible,
+    BigMode,
+    SpawnPiece,
+    SpawnRotation,
+    DropDistance,
+    Finesse,
+    FillGauge,
+    RookieAssist,
+    GhostClears,
+    SecondChance,
+    GameLog,
+    BlockStyle,
+    BlockOutline,
+    GridLines,
+    PieceColors,
+    ControlsHud,
+    // Rows of the piece color screen.
+    ColorPiece,
+    ColorRed,
+    ColorGreen,
+    ColorBlue,
+    ResetColors,
+}
+
+impl MenuItem {
+    const ALL: [MenuItem; 26] = [
+        MenuItem::Mode,
+        MenuItem::Randomizer,
+        MenuItem::ReducedMotion,
+        MenuItem::Music,
+        MenuItem::MusicVolume,
+        MenuItem::LockOut,
+        MenuItem::Hold,
+        MenuItem::HoldLimit,
+        MenuItem::HoldCostsTime,
+        MenuItem::NextCount,
+        MenuItem::Invisible,
+        MenuItem::BigMode,
+        MenuItem::SpawnPiece,
+        MenuItem::SpawnRotation,
+        MenuItem::DropDistance,
+        MenuItem::Finesse,
+        MenuItem::FillGauge,
+        MenuItem::RookieAssist,
+        MenuItem::GhostClears,
+        MenuItem::SecondChance,
+        MenuItem::GameLog,
+        MenuItem::BlockStyle,
+        MenuItem::BlockOutline,
+        MenuItem::GridLines,
+        MenuItem::PieceColors,
+        MenuItem::ControlsHud,
+    ];
+
+    // The subset offered by the in-game settings overlay: presentation and
+    // audio only, nothing that changes the rules of a game in progress.
+    const IN_GAME: [MenuItem; 8] = [
+        MenuItem::Music,
+        MenuItem::MusicVolume,
+        MenuItem::ReducedMotion,
+        MenuItem::BlockStyle,
+        MenuItem::BlockOutline,
+        MenuItem::GridLines,
+        MenuItem::DropDistance,
+        MenuItem::FillGauge,
+    ];
+
+    // The piece color screen, opened from the Piece Colors row. Only the
+    // seven tetrominoes are editable; pentominoes keep the NES palette.
+    const COLOR_EDITOR: [MenuItem; 5] = [
+        MenuItem::ColorPiece,
+        MenuItem::ColorRed,
+        MenuItem::ColorGreen,
+        MenuItem::ColorBlue,
+        MenuItem::ResetColors,
+    ];
+}
+
 // Tetromino definitions and game structures.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 enum TetrominoType {
     I, O, T, S, Z, J, L,
+    // Pentominoes, dealt only in Pento mode.
+    F5, I5, L5, N5, P5, T5, U5, V5, W5, X5, Y5, Z5,
     BonusGold, BonusSilver, // For bonus blocks.
 }
 
-const TETROMINO_SHAPES: [[[i32; 2]; 4]; 7] = [
-    [[0,0],[1,0],[2,0],[3,0]],    // I
-    [[0,0],[1,0],[0,1],[1,1]],    // O
-    [[1,0],[0,1],[1,1],[2,1]],    // T
-    [[1,0],[2,0],[0,1],[1,1]],    // S
-    [[0,0],[1,0],[1,1],[2,1]],    // Z
-    [[0,0],[0,1],[1,1],[2,1]],    // J
-    [[0,0],[1,0],[2,0],[0,1]],    // L
+const TETROMINOES: [TetrominoType; 7] = [
+    TetrominoType::I,
+    TetrominoType::O,
+    TetrominoType::T,
+    TetrominoType::S,
+    TetrominoType::Z,
+    TetrominoType::J,
+    TetrominoType::L,
+];
+
+const PENTOMINOES: [TetrominoType; 12] = [
+    TetrominoType::F5,
+    TetrominoType::I5,
+    TetrominoType::L5,
+    TetrominoType::N5,
+    TetrominoType::P5,
+    TetrominoType::T5,
+    TetrominoType::U5,
+    TetrominoType::V5,
+    TetrominoType::W5,
+    TetrominoType::X5,
+    TetrominoType::Y5,
+    TetrominoType::Z5,
+];
+
+const MAX_CELLS: usize = 5;
+
+// Cell offsets of a piece: four for tetrominoes, five for pentominoes.
+// Derefs to the slice of cells in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Shape {
+    cells: [[i32; 2]; MAX_CELLS],
+    len: usize,
+}
+
+impl Shape {
+    const fn tetromino(c: [[i32; 2]; 4]) -> Self {
+        Shape { cells: [c[0], c[1], c[2], c[3], [0, 0]], len: 4 }
+    }
+
+    const fn pentomino(cells: [[i32; 2]; 5]) -> Self {
+        Shape { cells, len: 5 }
+    }
+}
+
+impl Deref for Shape {
+    type Target = [[i32; 2]];
+
+    fn deref(&self) -> &Self::Target {
+        &self.cells[..self.len]
+    }
+}
+
+impl DerefMut for Shape {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.cells[..self.len]
+    }
+}
+
+impl<'a> IntoIterator for &'a Shape {
+    type Item = &'a [i32; 2];
+    type IntoIter = std::slice::Iter<'a, [i32; 2]>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+const TETROMINO_SHAPES: [Shape; 19] = [
+    Shape::tetromino([[0,0],[1,0],[2,0],[3,0]]),          // I
+    Shape::tetromino([[0,0],[1,0],[0,1],[1,1]]),          // O
+    Shape::tetromino([[1,0],[0,1],[1,1],[2,1]]),          // T
+    Shape::tetromino([[1,0],[2,0],[0,1],[1,1]]),          // S
+    Shape::tetromino([[0,0],[1,0],[1,1],[2,1]]),          // Z
+    Shape::tetromino([[0,0],[0,1],[1,1],[2,1]]),          // J
+    Shape::tetromino([[0,0],[1,0],[2,0],[0,1]]),          // L
+    Shape::pentomino([[1,0],[2,0],[0,1],[1,1],[1,2]]),    // F5
+    Shape::pentomino([[0,0],[1,0],[2,0],[3,0],[4,0]]),    // I5
+    Shape::pentomino([[0,0],[1,0],[2,0],[3,0],[0,1]]),    // L5
+    Shape::pentomino([[0,0],[1,0],[1,1],[2,1],[3,1]]),    // N5
+    Shape::pentomino([[0,0],[1,0],[2,0],[0,1],[1,1]]),    // P5
+    Shape::pentomino([[0,0],[1,0],[2,0],[1,1],[1,2]]),    // T5
+    Shape::pentomino([[0,0],[2,0],[0,1],[1,1],[2,1]]),    // U5
+    Shape::pentomino([[0,0],[0,1],[0,2],[1,2],[2,2]]),    // V5
+    Shape::pentomino([[0,0],[0,1],[1,1],[1,2],[2,2]]),    // W5
+    Shape::pentomino([[1,0],[0,1],[1,1],[2,1],[1,2]]),    // X5
+    Shape::pentomino([[1,0],[0,1],[1,1],[2,1],[3,1]]),    // Y5
+    Shape::pentomino([[0,0],[1,0],[1,1],[1,2],[2,2]]),    // Z5
 ];
 
-const TETROMINO_ROTATION_OFFSETS: [[i32; 2]; 7] = [
+const TETROMINO_ROTATION_OFFSETS: [[i32; 2]; 19] = [
     [1,0], // I
     [0,0], // O (doesn't rotate)
     [1,1], // T
@@ -139,11 +651,23 @@ const TETROMINO_ROTATION_OFFSETS: [[i32; 2]; 7] = [
     [1,1], // Z
     [1,1], // J
     [1,1], // L
+    [1,1], // F5
+    [2,0], // I5
+    [1,0], // L5
+    [1,1], // N5
+    [1,0], // P5
+    [1,1], // T5
+    [1,1], // U5
+    [1,1], // V5
+    [1,1], // W5
+    [1,1], // X5
+    [1,1], // Y5
+    [1,1], // Z5
 ];
 
 #[derive(Clone, Copy)]
 struct Tetromino {
-    shape: [[i32; 2]; 4],
+    shape: Shape,
     pos: (i32, i32),
     color: Color,
     t_type: TetrominoType,
@@ -160,8 +684,8 @@ impl Tetromino {
     }
 }
 
-fn rotate_shape(shape: &[[i32; 2]; 4], t_type: TetrominoType, clockwise: bool) -> [[i32; 2]; 4] {
-    let mut new_shape = [[0; 2]; 4];
+fn rotate_shape(shape: &Shape, t_type: TetrominoType, clockwise: bool) -> Shape {
+    let mut new_shape = *shape;
     let [pivot_x, pivot_y] = TETROMINO_ROTATION_OFFSETS[t_type as usize];
     for (i, &[x, y]) in shape.iter().enumerate() {
         let rel_x = x - pivot_x;
@@ -176,6 +700,119 @@ fn rotate_shape(shape: &[[i32; 2]; 4], t_type: TetrominoType, clockwise: bool) -
     new_shape
 }
 
+// Cells of a placement as absolute columns and rows relative to its top,
+// so placements can be compared regardless of drop height.
+fn footprint(shape: &[[i32; 2]], x: i32) -> Vec<(i32, i32)> {
+    let top = shape.iter().map(|&[_, dy]| dy).min().unwrap_or(0);
+    let mut cells: Vec<(i32, i32)> = shape.iter().map(|&[dx, dy]| (x + dx, dy - top)).collect();
+    cells.sort();
+    cells
+}
+
+// Fewest inputs that bring the freshly spawned piece to the placement with
+// the given shape and column on an empty board. Taps, rotations and
+// holding a direction into the wall each count as one input.
+fn optimal_inputs(spawn: &Tetromino, shape: &[[i32; 2]], x: i32) -> Option<u32> {
+    let t_type = spawn.t_type;
+    let target = footprint(shape, x);
+    let fits = |shape: &[[i32; 2]], x: i32| {
+        shape.iter().all(|&[dx, _]| x + dx >= 0 && x + dx < GRID_WIDTH as i32)
+    };
+    let mut seen = vec![(spawn.shape, spawn.pos.0)];
+    let mut frontier = seen.clone();
+    let mut cost = 0;
+    while !frontier.is_empty() {
+        if frontier.iter().any(|(s, x)| footprint(s, *x) == target) {
+            return Some(cost);
+        }
+        let mut next = Vec::new();
+        for &(shape, x) in &frontier {
+            let mut wall_left = x;
+            while fits(&shape, wall_left - 1) { wall_left -= 1; }
+            let mut wall_right = x;
+            while fits(&shape, wall_right + 1) { wall_right += 1; }
+            let moves = [
+                (shape, x - 1),
+                (shape, x + 1),
+                (shape, wall_left),
+                (shape, wall_right),
+                (rotate_shape(&shape, t_type, true), x),
+                (rotate_shape(&shape, t_type, false), x),
+            ];
+            for m in moves {
+                if fits(&m.0, m.1) && !seen.contains(&m) {
+                    seen.push(m);
+                    next.push(m);
+                }
+            }
+        }
+        frontier = next;
+        cost += 1;
+    }
+    None
+}
+
+// A board cell: block color, the type of piece it came from, and that
+// piece's id (0 for bonus blocks).
+type Cell = Option<(Color, TetrominoType, u32)>;
+
+// Deletes `rows` from the board, dropping everything above them. Surviving
+// rows are compacted downward in place, walking bottom-up so a row is never
+// overwritten before it has been copied, then the rows left over at the
+// top are blanked.
+fn remove_rows(board: &mut [[Cell; GRID_WIDTH]; GRID_HEIGHT], rows: &[usize]) {
+    let mut write = GRID_HEIGHT;
+    for read in (0..GRID_HEIGHT).rev() {
+        if rows.contains(&read) { continue; }
+        write -= 1;
+        board[write] = board[read];
+    }
+    for row in board.iter_mut().take(write) {
+        *row = [None; GRID_WIDTH];
+    }
+}
+
+// Column names for the game log, matching GameState::game_log_row.
+fn game_log_header() -> String {
+    let mut header = String::from("timestamp,mode,score,lines,play_time");
+    for piece in TETROMINOES.iter().chain(PENTOMINOES.iter()) {
+        header.push_str(&format!(",{:?}", piece));
+    }
+    header
+}
+
+// Appends `row` to the CSV file at `path`, creating it with `header` as
+// its first line if it doesn't exist or is empty.
+fn append_csv_row(path: &str, header: &str, row: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", header)?;
+    }
+    writeln!(file, "{}", row)
+}
+
+// Replaces the file at `path` with `contents`. The new text goes to a
+// temporary file that is then renamed over the old one, so an interrupted
+// write leaves either the old file or the new one, never half of each.
+fn replace_file(path: &str, contents: &str) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+// Settings-file key of a tetromino's color, e.g. `color_t`.
+fn color_key(t_type: TetrominoType) -> String {
+    format!("color_{:?}", t_type).to_lowercase()
+}
+
+// Sets `field` from a settings-file value, leaving it alone if the value
+// doesn't parse.
+fn parse_into<T: FromStr>(field: &mut T, value: &str) {
+    if let Ok(parsed) = value.parse() {
+        *field = parsed;
+    }
+}
+
 struct SquareEffect {
     x: usize,
     y: usize,
@@ -186,28 +823,165 @@ struct SquareEffect {
     original: [[(Color, TetrominoType, u32); 4]; 4],
 }
 
+// Start-screen input latency test. Each sample runs from the frame that
+// first sees a key press to the start of the following frame, i.e. after
+// the frame reacting to it has been presented. Time spent in the OS and the
+// display before the game sees the press can't be observed from here.
+struct LatencyCalibration {
+    samples: VecDeque<f64>, // Seconds, most recent last.
+    pending: Option<f64>,   // When the last unmeasured press was seen.
+}
+
+impl LatencyCalibration {
+    fn new() -> Self {
+        LatencyCalibration { samples: VecDeque::with_capacity(LATENCY_SAMPLES), pending: None }
+    }
+
+    // Call once per frame, before anything else reads input.
+    fn update(&mut self) {
+        let now = get_time();
+        if let Some(pressed_at) = self.pending.take() {
+            if self.samples.len() == LATENCY_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(now - pressed_at);
+        }
+        if get_last_key_pressed().is_some() {
+            self.pending = Some(now);
+        }
+    }
+
+    fn draw(&self) {
+        let x = screen_width() / 2.0 - 250.0;
+        let mut y = 100.0;
+        draw_text("Input Latency Test", x, y, 40.0, YELLOW);
+        y += 40.0;
+        draw_text("Tap any key repeatedly. Esc returns to the menu.", x, y, 24.0, GRAY);
+        y += 50.0;
+        let ms: Vec<f64> = self.samples.iter().map(|s| s * 1000.0).collect();
+        draw_text(&format!("FPS: {}", get_fps()), x, y, 28.0, WHITE);
+        y += 34.0;
+        draw_text(&format!("Samples: {}", ms.len()), x, y, 28.0, WHITE);
+        y += 34.0;
+        if ms.is_empty() {
+            return;
+        }
+        let min = ms.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = ms.iter().copied().fold(0.0, f64::max);
+        let avg = ms.iter().sum::<f64>() / ms.len() as f64;
+        draw_text(&format!("Min {:.1} ms   Avg {:.1} ms   Max {:.1} ms", min, avg, max), x, y, 28.0, WHITE);
+        y += 30.0;
+
+        // Histogram, with the last bar collecting everything slower.
+        let mut buckets = [0u32; LATENCY_BUCKETS + 1];
+        for &sample in &ms {
+            buckets[((sample / LATENCY_BUCKET_MS) as usize).min(LATENCY_BUCKETS)] += 1;
+        }
+        let tallest = *buckets.iter().max().unwrap_or(&1) as f32;
+        let bar_w = 22.0;
+        let chart_h = 200.0;
+        let base_y = y + chart_h;
+        for (i, &count) in buckets.iter().enumerate() {
+            let h = chart_h * count as f32 / tallest;
+            let color = if i == LATENCY_BUCKETS { ORANGE } else { GREEN };
+            draw_rectangle(x + i as f32 * bar_w, base_y - h, bar_w - 2.0, h, color);
+        }
+        draw_line(x, base_y, x + buckets.len() as f32 * bar_w, base_y, 1.0, GRAY);
+        draw_text("0 ms", x, base_y + 20.0, 20.0, GRAY);
+        let last = format!("{:.0}+ ms", LATENCY_BUCKETS as f64 * LATENCY_BUCKET_MS);
+        draw_text(&last, x + LATENCY_BUCKETS as f32 * bar_w - 20.0, base_y + 20.0, 20.0, GRAY);
+    }
+}
+
+// Fading afterimage left behind by a hard drop.
+struct DropTrail {
+    cells: Vec<(i32, i32)>, // Board cells the piece passed through.
+    end_y: i32,             // Row of the piece when it locked.
+    color: Color,
+    timer: f32,
+}
+
 struct GameState {
     // Each cell stores Option<(Color, TetrominoType, piece_id)>
-    board: [[Option<(Color, TetrominoType, u32)>; GRID_WIDTH]; GRID_HEIGHT],
+    board: [[Cell; GRID_WIDTH]; GRID_HEIGHT],
     tetromino: Option<Tetromino>,
-    next_tetromino: Option<Tetromino>,
+    next_queue: VecDeque<Tetromino>, // Always NEXT_QUEUE_LEN long during play.
+    piece_gen: PieceGenerator,
+    next_preview_count: usize,        // How many queued pieces the HUD shows (1..=NEXT_QUEUE_LEN).
     hold_tetromino: Option<Tetromino>,
     hold_used: bool,
-
+    hold_enabled: bool,      // Variants without hold turn C off entirely.
+    hold_limit: Option<u32>, // Max holds per game; None for unlimited.
+    hold_costs_time: bool,   // Ultra modifier: each hold costs HOLD_TIME_COST seconds.
+    holds_used: u32,
+    // Cached landing row of the active piece; None when it must be recomputed.
+    ghost_y: Option<i32>,
+
+    mode: GameMode,
+    music_enabled: bool, // Whether games start with music audible.
+    reduced_motion: bool, // Replace flashing/blinking effects with calmer ones.
+    lock_out_enabled: bool, // Top out when a piece locks entirely in the hidden rows.
+    invisible_mode: bool,   // Challenge: locked pieces vanish after INVISIBLE_DELAY.
+    big_mode: bool,         // Novelty: pieces are built from BIG_SCALE x BIG_SCALE blocks.
+    spawn_rotations: [u8; TETROMINO_SHAPES.len()], // Clockwise quarter turns applied at spawn, per type.
+    spawn_edit_type: TetrominoType, // Piece the Spawn Rotation menu row edits.
+    piece_colors: [[f32; 3]; TETROMINOES.len()], // RGB per tetromino, overriding NES_COLORS.
+    color_edit_index: usize, // Index into TETROMINOES of the piece being recolored.
+    show_drop_distance: bool, // Training aid: rows a hard drop would travel.
+    finesse_training: bool,   // Training aid: count pieces placed with extra inputs.
+    show_fill_gauge: bool,    // HUD bar showing how much of the playfield is occupied.
+    rookie_assist: bool,      // New pieces hang until the first input or ROOKIE_WAIT_TIMEOUT.
+    ghost_clear_preview: bool, // Highlight the rows a hard drop would complete.
+    second_chance_assist: bool, // The first top-out of a game clears the top rows instead.
+    game_log_enabled: bool,   // Append each finished game to GAME_LOG_PATH.
+    block_style: BlockStyle,
+    block_outline: bool, // Dark 1px frame around every cell for contrast.
+    show_grid_lines: bool, // Faint cell grid over the board background.
+    layout: Layout,
+    settings_path: Option<&'static str>, // Where settings are saved; None keeps them in memory only.
+    menu_index: usize,
+    show_settings_overlay: bool,
+    paused_under_settings: bool, // Whether play was already paused when the overlay opened.
+    settings_index: usize,
+    show_color_editor: bool,
+    color_menu_index: usize,
+    show_controls_hud: bool,
+    show_controls_overlay: bool,
+    show_debug_overlay: bool,
+    calibration: Option<LatencyCalibration>, // Latency test screen, while open.
     started: bool,
     paused: bool,
     game_over: bool,
+    top_out_reason: Option<TopOut>,
+    second_chances_remaining: u32,
+    popup: Option<(String, f32)>, // Short message over the board and its time left.
+    game_over_anim_timer: f32, // Curtain fill still running while > 0.
     lines_cleared: u32,
     score: u32,
+    piece_inputs: u32,   // Movement/rotation presses spent on the active piece.
+    finesse_faults: u32, // Pieces placed with more inputs than necessary.
+    time_remaining: f32, // Ultra countdown in seconds, derived from effective_play_time.
+    time_penalty: f32,   // Seconds taken off the Ultra clock by modifiers.
+    play_time: f64, // Seconds of unpaused play this game.
+    timer_pulse: f32,    // 0.0..=1.0 flash of the low-time warning.
 
     left_timer: f32,
     right_timer: f32,
     fall_timer: f32,
+    waiting_for_input: bool, // Rookie assist: gravity held until the player acts.
+    input_wait_timer: f32,
 
     line_clear_timer: f32,
     clearing_lines: Vec<usize>,
+    square_freeze_timer: f32, // Time play has been held for the current bonus-square blink.
+
+    danger_level: f32, // 0.0..=1.0, eases toward 1.0 while the stack is near the top.
+    music_speed: f32,  // Smoothed music tempo driven by stack height.
 
     active_squares: Vec<SquareEffect>,
+    drop_trails: Vec<DropTrail>,
+    recently_locked: Vec<(u32, f32)>, // (piece_id, flash time left)
+    visible_until: HashMap<u32, f64>, // piece_id -> time its cells disappear (invisible mode)
 
     next_piece_id: u32, // For unique locked piece tagging.
 
@@ -219,6 +993,13 @@ struct GameState {
 
 impl GameState {
     pub fn new() -> Self {
+        let mut game = Self::with_music(MusicManager::new());
+        game.settings_path = Some(SETTINGS_PATH);
+        game.load_settings();
+        game
+    }
+
+    fn with_music(mus_mgr: MusicManager) -> Self {
         let mut piece_statistics = HashMap::new();
         // Initialize counter for the main tetromino types.
         for &piece in &[
@@ -236,86 +1017,205 @@ impl GameState {
         Self {
             board: [[None; GRID_WIDTH]; GRID_HEIGHT],
             tetromino: None,
-            next_tetromino: None,
+            next_queue: VecDeque::with_capacity(NEXT_QUEUE_LEN),
+            piece_gen: PieceGenerator::new(Randomizer::TrueRandom),
+            next_preview_count: 1,
             hold_tetromino: None,
             hold_used: false,
+            hold_enabled: true,
+            hold_limit: None,
+            hold_costs_time: false,
+            holds_used: 0,
+            ghost_y: None,
+            mode: GameMode::Classic,
+            music_enabled: true,
+            reduced_motion: false,
+            lock_out_enabled: true,
+            invisible_mode: false,
+            big_mode: false,
+            spawn_rotations: [0; TETROMINO_SHAPES.len()],
+            spawn_edit_type: TetrominoType::I,
+            piece_colors: nes_piece_colors(),
+            color_edit_index: 0,
+            show_drop_distance: false,
+            finesse_training: false,
+            show_fill_gauge: false,
+            rookie_assist: false,
+            ghost_clear_preview: false,
+            second_chance_assist: false,
+            game_log_enabled: false,
+            block_style: BlockStyle::Bevel,
+            block_outline: false,
+            show_grid_lines: false,
+            layout: Layout::Full,
+            settings_path: None,
+            menu_index: 0,
+            show_settings_overlay: false,
+            paused_under_settings: false,
+            settings_index: 0,
+            show_color_editor: false,
+            color_menu_index: 0,
+            show_controls_hud: false,
+            show_controls_overlay: false,
+            show_debug_overlay: false,
+            calibration: None,
             started: false,
             paused: false,
             game_over: false,
+            top_out_reason: None,
+            second_chances_remaining: 0,
+            popup: None,
+            game_over_anim_timer: 0.0,
             lines_cleared: 0,
             score: 0,
+            piece_inputs: 0,
+            finesse_faults: 0,
+            time_remaining: ULTRA_DURATION,
+            time_penalty: 0.0,
+            play_time: 0.0,
+            timer_pulse: 0.0,
             left_timer: 0.0,
             right_timer: 0.0,
             fall_timer: 0.0,
+            waiting_for_input: false,
+            input_wait_timer: 0.0,
             line_clear_timer: 0.0,
             clearing_lines: Vec::new(),
+            square_freeze_timer: 0.0,
+            danger_level: 0.0,
+            music_speed: 1.0,
             active_squares: Vec::new(),
+            drop_trails: Vec::new(),
+            recently_locked: Vec::new(),
+            visible_until: HashMap::new(),
             next_piece_id: 1,
-            mus_mgr: MusicManager::new(),
+            mus_mgr,
             piece_statistics,
         }
     }
 
     pub fn start_game(&mut self) {
+        // Remember the menu choices this game was started with.
+        self.save_settings();
         self.started = true;
         self.game_over = false;
+        self.top_out_reason = None;
+        self.second_chances_remaining = 1;
+        self.popup = None;
+        self.game_over_anim_timer = 0.0;
         self.paused = false;
         self.lines_cleared = 0;
         self.score = 0;
+        self.piece_inputs = 0;
+        self.finesse_faults = 0;
+        self.time_remaining = ULTRA_DURATION;
+        self.time_penalty = 0.0;
+        self.play_time = 0.0;
+        self.timer_pulse = 0.0;
         self.board = [[None; GRID_WIDTH]; GRID_HEIGHT];
         self.hold_tetromino = None;
         self.hold_used = false;
+        self.holds_used = 0;
         self.line_clear_timer = 0.0;
         self.clearing_lines.clear();
+        self.square_freeze_timer = 0.0;
+        self.danger_level = 0.0;
+        self.music_speed = 1.0;
         self.active_squares.clear();
+        self.drop_trails.clear();
+        self.recently_locked.clear();
+        self.visible_until.clear();
         self.next_piece_id = 1;
 
         // Reset statistics at the start of a new game.
         self.piece_statistics.clear();
-        for &piece in &[
-            TetrominoType::I,
-            TetrominoType::O,
-            TetrominoType::T,
-            TetrominoType::S,
-            TetrominoType::Z,
-            TetrominoType::J,
-            TetrominoType::L,
-        ] {
+        for &piece in self.mode.piece_set() {
             self.piece_statistics.insert(piece, 0);
         }
 
-        let mut rng = thread_rng();
-        let curr_type = match rng.gen_range(0..7) {
-            0 => TetrominoType::I,
-            1 => TetrominoType::O,
-            2 => TetrominoType::T,
-            3 => TetrominoType::S,
-            4 => TetrominoType::Z,
-            5 => TetrominoType::J,
-            _ => TetrominoType::L,
-        };
-        let next_type = match rng.gen_range(0..7) {
-            0 => TetrominoType::I,
-            1 => TetrominoType::O,
-            2 => TetrominoType::T,
-            3 => TetrominoType::S,
-            4 => TetrominoType::Z,
-            5 => TetrominoType::J,
-            _ => TetrominoType::L,
-        };
+        self.piece_gen.reset();
+        let curr_type = self.piece_gen.next_piece(self.mode.piece_set());
 
-        self.tetromino = Some(Tetromino::new(curr_type));
+        self.tetromino = Some(self.new_piece(curr_type));
+        self.ghost_y = None;
+        self.hold_for_first_input();
         // Count the active tetromino spawn.
         *self.piece_statistics.entry(curr_type).or_insert(0) += 1;
 
-        self.next_tetromino = Some(Tetromino::new(next_type));
+        self.next_queue.clear();
+        while self.next_queue.len() < NEXT_QUEUE_LEN {
+            let t_type = self.piece_gen.next_piece(self.mode.piece_set());
+            self.next_queue.push_back(self.new_piece(t_type));
+        }
+        // Start the track even with music off so M or N can bring it in later.
+        self.mus_mgr.set_muted(!self.music_enabled);
         self.mus_mgr.play_song();
     }
 
-    pub fn check_collision(&self, shape: &[[i32; 2]; 4], pos: (i32, i32)) -> bool {
+    // Board cells per side of one piece cell.
+    fn cell_scale(&self) -> i32 {
+        if self.big_mode { BIG_SCALE } else { 1 }
+    }
+
+    // Board cells covered by `shape` at `pos`. In big mode `pos` and the
+    // shape count in coarse cells, each covering a square of board cells.
+    fn piece_cells(&self, shape: &[[i32; 2]], pos: (i32, i32)) -> Vec<(i32, i32)> {
+        let scale = self.cell_scale();
+        let mut cells = Vec::with_capacity(shape.len() * (scale * scale) as usize);
         for &[dx, dy] in shape {
-            let x = pos.0 + dx;
-            let y = pos.1 + dy;
+            for sy in 0..scale {
+                for sx in 0..scale {
+                    cells.push(((pos.0 + dx) * scale + sx, (pos.1 + dy) * scale + sy));
+                }
+            }
+        }
+        cells
+    }
+
+    // Where a piece enters the board, with its top cell on the first row.
+    // Big mode centres it on the coarse grid.
+    fn spawn_pos(&self, shape: &[[i32; 2]]) -> (i32, i32) {
+        let min_y = shape.iter().map(|&[_, dy]| dy).min().unwrap_or(0);
+        if !self.big_mode {
+            return (GRID_WIDTH as i32 / 2 - 2, -min_y);
+        }
+        let min_x = shape.iter().map(|&[dx, _]| dx).min().unwrap_or(0);
+        let max_x = shape.iter().map(|&[dx, _]| dx).max().unwrap_or(0);
+        let columns = GRID_WIDTH as i32 / BIG_SCALE;
+        ((columns - (max_x - min_x + 1)) / 2 - min_x, -min_y)
+    }
+
+    // A piece's shape in its configured spawn orientation.
+    fn spawn_shape(&self, t_type: TetrominoType) -> Shape {
+        let mut shape = TETROMINO_SHAPES[t_type as usize];
+        for _ in 0..self.spawn_rotations[t_type as usize] {
+            shape = rotate_shape(&shape, t_type, true);
+        }
+        shape
+    }
+
+    // A piece's color: the player's choice for tetrominoes, the NES
+    // palette for everything else.
+    fn piece_color(&self, t_type: TetrominoType) -> Color {
+        match TETROMINOES.iter().position(|&t| t == t_type) {
+            Some(i) => {
+                let [r, g, b] = self.piece_colors[i];
+                Color::new(r, g, b, 1.0)
+            }
+            None => NES_COLORS[t_type as usize],
+        }
+    }
+
+    fn new_piece(&self, t_type: TetrominoType) -> Tetromino {
+        let mut piece = Tetromino::new(t_type);
+        piece.color = self.piece_color(t_type);
+        piece.shape = self.spawn_shape(t_type);
+        piece.pos = self.spawn_pos(&piece.shape);
+        piece
+    }
+
+    pub fn check_collision(&self, shape: &[[i32; 2]], pos: (i32, i32)) -> bool {
+        for (x, y) in self.piece_cells(shape, pos) {
             if x < 0 || x >= GRID_WIDTH as i32 || y < 0 || y >= GRID_HEIGHT as i32 {
                 return true;
             }
@@ -327,16 +1227,37 @@ impl GameState {
     }
 
     pub fn lock_tetromino(&mut self) {
-        if let Some(tetro) = self.tetromino {
+        let mut locked_out = false;
+        // Once its cells are on the board the piece is gone, so nothing later
+        // in the frame can lock it a second time.
+        if let Some(tetro) = self.tetromino.take() {
             let id = self.next_piece_id;
             self.next_piece_id += 1;
-            for &[dx, dy] in &tetro.shape {
-                let x = tetro.pos.0 + dx;
-                let y = tetro.pos.1 + dy;
+            if !self.reduced_motion {
+                self.recently_locked.push((id, LOCK_FLASH_DURATION));
+            }
+            if self.invisible_mode {
+                self.visible_until.insert(id, get_time() + INVISIBLE_DELAY);
+            }
+            let cells = self.piece_cells(&tetro.shape, tetro.pos);
+            for &(x, y) in &cells {
                 if x >= 0 && x < GRID_WIDTH as i32 && y >= 0 && y < GRID_HEIGHT as i32 {
                     self.board[y as usize][x as usize] = Some((tetro.color, tetro.t_type, id));
                 }
             }
+            locked_out = cells.iter().all(|&(_, y)| y < HIDDEN_ROWS as i32);
+            // The optimal-input search assumes the normal grid.
+            if self.finesse_training && !self.big_mode {
+                let optimal = optimal_inputs(&self.new_piece(tetro.t_type), &tetro.shape, tetro.pos.0);
+                if optimal.is_some_and(|n| self.piece_inputs > n) {
+                    self.finesse_faults += 1;
+                }
+            }
+        }
+        self.ghost_y = None;
+        if locked_out && self.lock_out_enabled && self.mode != GameMode::Zen {
+            self.top_out(TopOut::LockOut);
+            return;
         }
         let mut full_rows = Vec::new();
         for (i, row) in self.board.iter().enumerate() {
@@ -354,22 +1275,14 @@ impl GameState {
     }
 
     pub fn clear_lines_delayed(&mut self) {
-        let mut new_board: Vec<[Option<(Color, TetrominoType, u32)>; GRID_WIDTH]> = Vec::new();
-        for (i, row) in self.board.iter().enumerate() {
-            if self.clearing_lines.contains(&i) { continue; }
-            new_board.push(*row);
-        }
-        while new_board.len() < GRID_HEIGHT {
-            new_board.insert(0, [None; GRID_WIDTH]);
-        }
-        self.board = new_board.try_into().unwrap();
+        remove_rows(&mut self.board, &self.clearing_lines);
+        self.ghost_y = None;
         self.lines_cleared += self.clearing_lines.len() as u32;
         self.clearing_lines.clear();
 
-        if let Some(next) = self.next_tetromino {
-            if self.check_collision(&next.shape, next.pos) {
-                self.game_over = true;
-                self.started = false;
+        if let Some(&next) = self.next_queue.front() {
+            if self.mode != GameMode::Zen && self.check_collision(&next.shape, next.pos) {
+                self.top_out(TopOut::BlockOut);
                 return;
             }
         }
@@ -379,67 +1292,195 @@ impl GameState {
 
     pub fn spawn_new_tetromino(&mut self) {
         if !self.started { return; }
-        if let Some(next_t) = self.next_tetromino {
+        if let Some(&next_t) = self.next_queue.front() {
+            // Zen never tops out: push the stack down until the spawn fits.
+            if self.mode == GameMode::Zen {
+                let mut shifts = 0;
+                while self.check_collision(&next_t.shape, next_t.pos) && shifts < MAX_STACK_SHIFTS {
+                    // Shift whole coarse rows so big-mode blocks stay aligned.
+                    for _ in 0..self.cell_scale() {
+                        self.shift_stack_down();
+                    }
+                    shifts += 1;
+                }
+            }
             if self.check_collision(&next_t.shape, next_t.pos) {
-                self.game_over = true;
-                self.started = false;
+                self.top_out(TopOut::BlockOut);
             } else {
                 self.tetromino = Some(next_t);
+                self.ghost_y = None;
+                self.piece_inputs = 0;
                 // Increment the statistics for the newly spawned tetromino.
                 *self.piece_statistics.entry(next_t.t_type).or_insert(0) += 1;
 
-                let mut rng = thread_rng();
-                let t_type = match rng.gen_range(0..7) {
-                    0 => TetrominoType::I,
-                    1 => TetrominoType::O,
-                    2 => TetrominoType::T,
-                    3 => TetrominoType::S,
-                    4 => TetrominoType::Z,
-                    5 => TetrominoType::J,
-                    _ => TetrominoType::L,
-                };
-                self.next_tetromino = Some(Tetromino::new(t_type));
+                self.next_queue.pop_front();
+                let t_type = self.piece_gen.next_piece(self.mode.piece_set());
+                self.next_queue.push_back(self.new_piece(t_type));
                 self.hold_used = false;
                 self.fall_timer = 0.0;
+                self.hold_for_first_input();
             }
         }
     }
 
-    // --- Square Detection ---
-    // Only triggers when every cell in a 4x4 candidate is full (and not bonus) and for every piece present,
-    // all its locked cells lie entirely within the candidate.
-    pub fn check_for_4x4_squares(&mut self) {
-        for y in 0..(GRID_HEIGHT - 3) {
-            for x in 0..(GRID_WIDTH - 3) {
-                let mut all_filled = true;
-                let mut original: [[(Color, TetrominoType, u32); 4]; 4] =
-                    [[(BLACK_COLOR, TetrominoType::I, 0); 4]; 4];
-                for dy in 0..4 {
-                    for dx in 0..4 {
-                        if let Some(cell) = self.board[y + dy][x + dx] {
-                            if cell.1 == TetrominoType::BonusGold || cell.1 == TetrominoType::BonusSilver {
-                                all_filled = false;
-                                break;
-                            }
-                            original[dy][dx] = cell;
-                        } else {
-                            all_filled = false;
-                            break;
-                        }
-                    }
-                    if !all_filled {
-                        break;
-                    }
-                }
-                if !all_filled {
-                    continue;
-                }
-                let mut pieces_in_region = vec![];
-                for row in &original {
-                    for &(_, _t, id) in row {
-                        if !pieces_in_region.contains(&id) {
-                            pieces_in_region.push(id);
-                        }
+    // Called once when the window is closed. Ends a game still in progress
+    // so the game log gets its row, saves the settings and stops the music.
+    pub fn shutdown(&mut self) {
+        if self.started {
+            self.end_game();
+        }
+        self.save_settings();
+        self.mus_mgr.reset();
+    }
+
+    // With the rookie assist on, keeps a fresh piece at the top until the
+    // player moves it or the wait times out.
+    fn hold_for_first_input(&mut self) {
+        self.waiting_for_input = self.rookie_assist;
+        self.input_wait_timer = 0.0;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused { return; }
+        self.paused = paused;
+        self.mus_mgr.set_paused(paused);
+    }
+
+    // Adds a frame's time to the play clock unless the game is paused or
+    // over. update calls this before any of its early returns, so every
+    // frame of a running game is counted exactly once.
+    pub fn advance_play_clock(&mut self, dt: f64) {
+        if !self.started || self.game_over || self.paused { return; }
+        self.play_time += dt;
+    }
+
+    // Seconds played this game. It is summed frame by frame rather than
+    // taken from the wall clock, so pauses simply never get added and it
+    // stops when the game ends. Mode timers derive from this.
+    pub fn effective_play_time(&self) -> f64 {
+        self.play_time
+    }
+
+    // Appends this game as a CSV row to `path`, writing the header first
+    // when the file is new.
+    pub fn append_game_log(&self, path: &str) -> std::io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        append_csv_row(path, &game_log_header(), &self.game_log_row(timestamp))
+    }
+
+    // This game's game-log row. See GAME_LOG_PATH for the column order.
+    fn game_log_row(&self, timestamp: u64) -> String {
+        let mut row = format!(
+            "{},{},{},{},{:.1}",
+            timestamp,
+            self.mode.name(),
+            self.score,
+            self.lines_cleared,
+            self.effective_play_time(),
+        );
+        for piece in TETROMINOES.iter().chain(PENTOMINOES.iter()) {
+            row.push_str(&format!(",{}", self.piece_statistics.get(piece).unwrap_or(&0)));
+        }
+        row
+    }
+
+    // Freezes the board and shows the game-over screen. Only the first call
+    // of a game counts, so the log gets one row per game.
+    pub fn end_game(&mut self) {
+        if self.game_over { return; }
+        self.game_over = true;
+        self.started = false;
+        // H only works mid-game, so don't leave the help covering the result.
+        self.show_controls_overlay = false;
+        if self.game_log_enabled {
+            // A failed write shouldn't take the game-over screen down with it.
+            if let Err(err) = self.append_game_log(GAME_LOG_PATH) {
+                eprintln!("Could not write to {}: {}", GAME_LOG_PATH, err);
+            }
+        }
+    }
+
+    // Ends the game on a top-out, curtaining the board before the text shows.
+    // With the second-chance assist, the first top-out clears room instead.
+    pub fn top_out(&mut self, reason: TopOut) {
+        if self.game_over { return; }
+        if self.second_chance_assist && self.second_chances_remaining > 0 {
+            self.second_chances_remaining -= 1;
+            self.use_second_chance();
+            return;
+        }
+        self.end_game();
+        self.top_out_reason = Some(reason);
+        if !self.reduced_motion {
+            self.game_over_anim_timer = GAME_OVER_ANIM_DURATION;
+        }
+    }
+
+    // Empties the hidden rows and the top SECOND_CHANCE_ROWS visible rows,
+    // charges the score penalty and deals the next piece.
+    fn use_second_chance(&mut self) {
+        let cleared = HIDDEN_ROWS + SECOND_CHANCE_ROWS;
+        for row in self.board.iter_mut().take(cleared) {
+            *row = [None; GRID_WIDTH];
+        }
+        self.active_squares.retain(|eff| eff.y >= cleared);
+        self.clearing_lines.clear();
+        self.ghost_y = None;
+        self.score = self.score.saturating_sub(SECOND_CHANCE_PENALTY);
+        self.popup = Some(("Second Chance!".to_string(), POPUP_DURATION));
+        self.tetromino = None;
+        self.spawn_new_tetromino();
+    }
+
+    // Moves every row down by one, discarding the bottom row.
+    pub fn shift_stack_down(&mut self) {
+        self.board.copy_within(0..GRID_HEIGHT - 1, 1);
+        self.board[0] = [None; GRID_WIDTH];
+        self.ghost_y = None;
+        // Bonus-square effects follow their cells; drop any pushed off the bottom.
+        self.active_squares.retain_mut(|eff| {
+            eff.y += 1;
+            eff.y + 4 <= GRID_HEIGHT
+        });
+    }
+
+    // --- Square Detection ---
+    // Only triggers when every cell in a 4x4 candidate is full (and not bonus) and for every piece present,
+    // all its locked cells lie entirely within the candidate.
+    pub fn check_for_4x4_squares(&mut self) {
+        // A single big-mode O already fills a 4x4, so bonus squares are off.
+        if self.big_mode { return; }
+        for y in 0..(GRID_HEIGHT - 3) {
+            for x in 0..(GRID_WIDTH - 3) {
+                let mut all_filled = true;
+                let mut original: [[(Color, TetrominoType, u32); 4]; 4] =
+                    [[(BLACK_COLOR, TetrominoType::I, 0); 4]; 4];
+                for (dy, original_row) in original.iter_mut().enumerate() {
+                    for (dx, original_cell) in original_row.iter_mut().enumerate() {
+                        if let Some(cell) = self.board[y + dy][x + dx] {
+                            if cell.1 == TetrominoType::BonusGold || cell.1 == TetrominoType::BonusSilver {
+                                all_filled = false;
+                                break;
+                            }
+                            *original_cell = cell;
+                        } else {
+                            all_filled = false;
+                            break;
+                        }
+                    }
+                    if !all_filled {
+                        break;
+                    }
+                }
+                if !all_filled {
+                    continue;
+                }
+                let mut pieces_in_region = vec![];
+                for row in &original {
+                    for &(_, _t, id) in row {
+                        if !pieces_in_region.contains(&id) {
+                            pieces_in_region.push(id);
+                        }
                     }
                 }
                 let mut candidate_valid = true;
@@ -447,11 +1488,9 @@ impl GameState {
                     for row in 0..GRID_HEIGHT {
                         for col in 0..GRID_WIDTH {
                             if let Some((_col, _t, id)) = self.board[row][col] {
-                                if id == pid {
-                                    if col < x || col >= x + 4 || row < y || row >= y + 4 {
-                                        candidate_valid = false;
-                                        break;
-                                    }
+                                if id == pid && (col < x || col >= x + 4 || row < y || row >= y + 4) {
+                                    candidate_valid = false;
+                                    break;
                                 }
                             }
                         }
@@ -468,10 +1507,10 @@ impl GameState {
                 }
                 let mut types = vec![];
                 for &pid in &pieces_in_region {
-                    'outer: for dy in 0..4 {
-                        for dx in 0..4 {
-                            if original[dy][dx].2 == pid {
-                                types.push(original[dy][dx].1);
+                    'outer: for original_row in &original {
+                        for &(_, t, id) in original_row {
+                            if id == pid {
+                                types.push(t);
                                 break 'outer;
                             }
                         }
@@ -488,14 +1527,19 @@ impl GameState {
                     timer: 0.3,
                     flash_on: true,
                     blinks_remaining: 6,
-                    original: original,
+                    original,
                 });
             }
         }
     }
 
     pub fn update_square_effects(&mut self, dt: f32) {
+        let reduced_motion = self.reduced_motion;
         self.active_squares.retain_mut(|eff| {
+            // Reduced motion skips the blink and fills the square at once.
+            if reduced_motion {
+                eff.blinks_remaining = 0;
+            }
             eff.timer -= dt;
             if eff.timer <= 0.0 {
                 eff.timer = 0.3;
@@ -527,6 +1571,7 @@ impl GameState {
     pub fn process_input(&mut self, delta: f32) {
         // Hard Drop: We use a separate block to avoid mutable/immutable borrow conflict.
         if is_key_pressed(KeyCode::Up) {
+            let start = self.tetromino;
             loop {
                 let can_move_down = {
                     if let Some(ref t) = self.tetromino {
@@ -540,24 +1585,38 @@ impl GameState {
                     t.pos.1 += 1;
                 }
             }
+            if !self.reduced_motion {
+                if let (Some(start), Some(end)) = (start, self.tetromino) {
+                    self.push_drop_trail(&start, end.pos.1);
+                }
+            }
             self.lock_tetromino();
             return;
         }
 
         // For other inputs, we can use a local copy.
         let curr = self.tetromino.unwrap();
+        for key in [KeyCode::Left, KeyCode::Right, KeyCode::Z, KeyCode::X] {
+            if is_key_pressed(key) {
+                self.waiting_for_input = false;
+            }
+        }
+        if is_key_down(KeyCode::Down) {
+            self.waiting_for_input = false;
+        }
+        // Finesse only counts presses that move or rotate the piece, so a
+        // rotation blocked by the top of the board or a tap into a wall is free.
         if is_key_pressed(KeyCode::Left) {
             if !self.check_collision(&curr.shape, (curr.pos.0 - 1, curr.pos.1)) {
                 self.move_tetromino((-1, 0));
+                self.piece_inputs += 1;
                 self.left_timer = INITIAL_HORIZONTAL_DELAY;
             }
         } else if is_key_down(KeyCode::Left) {
             self.left_timer -= delta;
-            if self.left_timer <= 0.0 {
-                if !self.check_collision(&curr.shape, (curr.pos.0 - 1, curr.pos.1)) {
-                    self.move_tetromino((-1, 0));
-                    self.left_timer = HORIZONTAL_REPEAT_DELAY;
-                }
+            if self.left_timer <= 0.0 && !self.check_collision(&curr.shape, (curr.pos.0 - 1, curr.pos.1)) {
+                self.move_tetromino((-1, 0));
+                self.left_timer = HORIZONTAL_REPEAT_DELAY;
             }
         } else {
             self.left_timer = 0.0;
@@ -566,142 +1625,739 @@ impl GameState {
         if is_key_pressed(KeyCode::Right) {
             if !self.check_collision(&curr.shape, (curr.pos.0 + 1, curr.pos.1)) {
                 self.move_tetromino((1, 0));
+                self.piece_inputs += 1;
                 self.right_timer = INITIAL_HORIZONTAL_DELAY;
             }
         } else if is_key_down(KeyCode::Right) {
             self.right_timer -= delta;
-            if self.right_timer <= 0.0 {
-                if !self.check_collision(&curr.shape, (curr.pos.0 + 1, curr.pos.1)) {
-                    self.move_tetromino((1, 0));
-                    self.right_timer = HORIZONTAL_REPEAT_DELAY;
-                }
+            if self.right_timer <= 0.0 && !self.check_collision(&curr.shape, (curr.pos.0 + 1, curr.pos.1)) {
+                self.move_tetromino((1, 0));
+                self.right_timer = HORIZONTAL_REPEAT_DELAY;
             }
         } else {
             self.right_timer = 0.0;
         }
 
-        if is_key_pressed(KeyCode::Z) {
-            let new_shape = rotate_shape(&curr.shape, curr.t_type, false);
-            if !self.check_collision(&new_shape, curr.pos) {
-                self.set_tetromino_shape(new_shape);
-            }
+        if is_key_pressed(KeyCode::Z) && self.rotate_tetromino(false) {
+            self.piece_inputs += 1;
         }
-        if is_key_pressed(KeyCode::X) {
-            let new_shape = rotate_shape(&curr.shape, curr.t_type, true);
-            if !self.check_collision(&new_shape, curr.pos) {
-                self.set_tetromino_shape(new_shape);
-            }
+        if is_key_pressed(KeyCode::X) && self.rotate_tetromino(true) {
+            self.piece_inputs += 1;
         }
 
-        if is_key_down(KeyCode::Down) {
-            self.fall_timer = 0.0;
-            if !self.check_collision(&curr.shape, (curr.pos.0, curr.pos.1 + 1)) {
-                self.move_tetromino((0, 1));
-            }
+        // Soft drop runs on the gravity timer at SOFT_DROP_SPEED; pressing
+        // Down makes the first step happen right away.
+        if is_key_pressed(KeyCode::Down) {
+            self.fall_timer = self.fall_interval(true);
         }
 
         if is_key_pressed(KeyCode::M) {
-            self.mus_mgr.mute();
+            self.toggle_music();
         }
 
         if is_key_pressed(KeyCode::N) {
             self.mus_mgr.play_song();
         }
 
-        if is_key_pressed(KeyCode::C) && !self.hold_used {
-            self.hold_used = true;
-            let mut current_piece = curr;
-            current_piece.shape = TETROMINO_SHAPES[current_piece.t_type as usize];
-            if let Some(mut hold_piece) = self.hold_tetromino.take() {
-                hold_piece.shape = TETROMINO_SHAPES[hold_piece.t_type as usize];
-                hold_piece.pos = (GRID_WIDTH as i32 / 2 - 2, 0);
-                if self.check_collision(&hold_piece.shape, hold_piece.pos) {
-                    self.hold_tetromino = Some(hold_piece);
-                } else {
-                    self.hold_tetromino = Some(current_piece);
-                    self.tetromino = Some(hold_piece);
-                }
+        if is_key_pressed(KeyCode::C) {
+            self.hold();
+        }
+    }
+
+    // Swaps the active piece with the held one, or stows it and deals the
+    // next piece when nothing is held. Does nothing when holding is off,
+    // already used for this piece, or out of holds for the game.
+    pub fn hold(&mut self) {
+        let Some(mut current_piece) = self.tetromino else { return };
+        if !self.hold_enabled || self.hold_used || self.holds_left() == Some(0) {
+            return;
+        }
+        self.hold_used = true;
+        current_piece.shape = self.spawn_shape(current_piece.t_type);
+        if let Some(mut hold_piece) = self.hold_tetromino.take() {
+            hold_piece.shape = self.spawn_shape(hold_piece.t_type);
+            hold_piece.pos = self.spawn_pos(&hold_piece.shape);
+            if self.check_collision(&hold_piece.shape, hold_piece.pos) {
+                self.hold_tetromino = Some(hold_piece);
             } else {
                 self.hold_tetromino = Some(current_piece);
-                self.tetromino = None;
-                self.spawn_new_tetromino();
+                self.tetromino = Some(hold_piece);
+                self.ghost_y = None;
+                self.piece_inputs = 0;
+                self.hold_for_first_input();
+                self.holds_used += 1;
+                self.charge_hold_time();
+            }
+        } else {
+            self.hold_tetromino = Some(current_piece);
+            self.tetromino = None;
+            self.holds_used += 1;
+            self.charge_hold_time();
+            self.spawn_new_tetromino();
+        }
+    }
+
+    // Seconds left on the Ultra clock after play time and hold penalties.
+    pub fn ultra_time_left(&self) -> f32 {
+        (ULTRA_DURATION - self.effective_play_time() as f32 - self.time_penalty).max(0.0)
+    }
+
+    // Ultra's hold-costs-time modifier. Running the clock out this way
+    // ends the game through the usual time-up check in update.
+    fn charge_hold_time(&mut self) {
+        if self.hold_costs_time && self.mode == GameMode::Ultra {
+            self.time_penalty += HOLD_TIME_COST;
+            self.popup = Some((format!("-{}s", HOLD_TIME_COST), POPUP_DURATION));
+        }
+    }
+
+    // Key bindings that apply to the current settings.
+    fn controls(&self) -> Vec<(&'static str, &'static str)> {
+        CONTROLS
+            .iter()
+            .copied()
+            .filter(|&(key, _)| self.hold_enabled || key != "C")
+            .collect()
+    }
+
+    // Holds remaining this game, or None when holding is unlimited.
+    pub fn holds_left(&self) -> Option<u32> {
+        self.hold_limit.map(|limit| limit.saturating_sub(self.holds_used))
+    }
+
+    fn push_drop_trail(&mut self, start: &Tetromino, end_y: i32) {
+        if end_y <= start.pos.1 {
+            return;
+        }
+        let landed = self.piece_cells(&start.shape, (start.pos.0, end_y));
+        let mut cells = Vec::new();
+        for y in start.pos.1..end_y {
+            for cell in self.piece_cells(&start.shape, (start.pos.0, y)) {
+                if !landed.contains(&cell) && !cells.contains(&cell) {
+                    cells.push(cell);
+                }
             }
         }
+        self.drop_trails.push(DropTrail {
+            cells,
+            end_y: end_y * self.cell_scale(),
+            color: start.color,
+            timer: DROP_TRAIL_DURATION,
+        });
+    }
+
+    pub fn update_drop_trails(&mut self, dt: f32) {
+        self.drop_trails.retain_mut(|trail| {
+            trail.timer -= dt;
+            trail.timer > 0.0
+        });
+    }
+
+    pub fn highest_occupied_row(&self) -> Option<usize> {
+        self.board.iter().position(|row| row.iter().any(|cell| cell.is_some()))
+    }
+
+    // Fraction of all board cells, hidden rows included, that are occupied.
+    pub fn board_fill(&self) -> f32 {
+        let filled = self.board.iter().flatten().filter(|cell| cell.is_some()).count();
+        filled as f32 / (GRID_WIDTH * GRID_HEIGHT) as f32
+    }
+
+    pub fn update_danger_level(&mut self, dt: f32) {
+        let in_danger = self
+            .highest_occupied_row()
+            .is_some_and(|row| row < HIDDEN_ROWS + DANGER_ROWS);
+        let target = if in_danger { 1.0 } else { 0.0 };
+        let step = dt * 4.0;
+        self.danger_level += (target - self.danger_level).clamp(-step, step);
+    }
+
+    // Speeds the music up as the stack climbs past the middle of the field.
+    pub fn update_music_tempo(&mut self, dt: f32) {
+        let target = if self.reduced_motion || self.mus_mgr.muted {
+            1.0
+        } else {
+            let height = self
+                .highest_occupied_row()
+                .map_or(0, |row| GRID_HEIGHT - row.max(HIDDEN_ROWS));
+            let half = VISIBLE_ROWS / 2;
+            let pressure = height.saturating_sub(half) as f32 / (VISIBLE_ROWS - half) as f32;
+            1.0 + (MUSIC_MAX_SPEED - 1.0) * pressure.min(1.0)
+        };
+        let step = dt * 0.1;
+        self.music_speed += (target - self.music_speed).clamp(-step, step);
+        self.mus_mgr.set_speed(self.music_speed);
+    }
+
+    pub fn update_lock_flashes(&mut self, dt: f32) {
+        self.recently_locked.retain_mut(|(_, timer)| {
+            *timer -= dt;
+            *timer > 0.0
+        });
     }
 
     pub fn move_tetromino(&mut self, (dx, dy): (i32, i32)) {
         if let Some(mut t) = self.tetromino {
             t.pos = (t.pos.0 + dx, t.pos.1 + dy);
             self.tetromino = Some(t);
+            self.ghost_y = None;
         }
     }
 
-    pub fn set_tetromino_shape(&mut self, shape: [[i32; 2]; 4]) {
+    pub fn set_tetromino_shape(&mut self, shape: Shape) {
         if let Some(mut t) = self.tetromino {
             t.shape = shape;
             self.tetromino = Some(t);
+            self.ghost_y = None;
+        }
+    }
+
+    // Rotates the active piece in place if it fits; returns whether it did.
+    pub fn rotate_tetromino(&mut self, clockwise: bool) -> bool {
+        let Some(curr) = self.tetromino else { return false };
+        let new_shape = rotate_shape(&curr.shape, curr.t_type, clockwise);
+        if self.check_collision(&new_shape, curr.pos) {
+            return false;
+        }
+        self.set_tetromino_shape(new_shape);
+        true
+    }
+
+    // Rows that would be full if the piece locked at pos, without touching
+    // the board.
+    fn rows_completed_by(&self, shape: &[[i32; 2]], pos: (i32, i32)) -> Vec<i32> {
+        let cells = self.piece_cells(shape, pos);
+        let mut rows: Vec<i32> = cells.iter().map(|&(_, y)| y).collect();
+        rows.sort();
+        rows.dedup();
+        rows.retain(|&y| {
+            y >= 0 && y < GRID_HEIGHT as i32 && (0..GRID_WIDTH as i32).all(|x| {
+                self.board[y as usize][x as usize].is_some() || cells.contains(&(x, y))
+            })
+        });
+        rows
+    }
+
+    // Row the active piece would land on if hard-dropped, cached until the
+    // piece moves, rotates, or the board changes.
+    pub fn ghost_y(&mut self) -> Option<i32> {
+        let curr = self.tetromino?;
+        if self.ghost_y.is_none() {
+            let mut y = curr.pos.1;
+            let mut iter = 0;
+            while !self.check_collision(&curr.shape, (curr.pos.0, y + 1)) && iter < 100 {
+                y += 1;
+                iter += 1;
+            }
+            self.ghost_y = Some(y);
+        }
+        self.ghost_y
+    }
+
+    // The settings file body: every menu and overlay choice, one
+    // `key=value` line each.
+    fn settings_text(&self) -> String {
+        let spawn_rotations: Vec<String> = self.spawn_rotations.iter().map(|t| t.to_string()).collect();
+        let lines = [
+            format!("mode={}", self.mode.name()),
+            format!("randomizer={}", self.piece_gen.kind.name()),
+            format!("reduced_motion={}", self.reduced_motion),
+            format!("music={}", self.music_enabled),
+            format!("music_volume={:.1}", self.mus_mgr.volume),
+            format!("lock_out={}", self.lock_out_enabled),
+            format!("hold={}", self.hold_enabled),
+            format!("hold_limit={}", self.hold_limit.map_or("unlimited".to_string(), |l| l.to_string())),
+            format!("hold_costs_time={}", self.hold_costs_time),
+            format!("next_pieces={}", self.next_preview_count),
+            format!("invisible={}", self.invisible_mode),
+            format!("big_mode={}", self.big_mode),
+            format!("spawn_rotations={}", spawn_rotations.join(",")),
+            format!("drop_distance={}", self.show_drop_distance),
+            format!("finesse={}", self.finesse_training),
+            format!("fill_gauge={}", self.show_fill_gauge),
+            format!("rookie_assist={}", self.rookie_assist),
+            format!("ghost_clears={}", self.ghost_clear_preview),
+            format!("second_chance={}", self.second_chance_assist),
+            format!("game_log={}", self.game_log_enabled),
+            format!("block_style={}", self.block_style.name()),
+            format!("block_outline={}", self.block_outline),
+            format!("grid_lines={}", self.show_grid_lines),
+            format!("controls_hud={}", self.show_controls_hud),
+            format!("layout={}", self.layout.name()),
+        ];
+        let mut text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        for (t, [r, g, b]) in TETROMINOES.iter().zip(self.piece_colors) {
+            text.push_str(&format!("{}={},{},{}\n", color_key(*t), r, g, b));
+        }
+        text
+    }
+
+    // Applies a settings file body. Unknown keys and values that don't
+    // parse are skipped, so an old or hand-edited file still loads.
+    fn apply_settings_text(&mut self, text: &str) {
+        for (key, value) in text.lines().filter_map(|line| line.split_once('=')) {
+            let value = value.trim();
+            match key.trim() {
+                "mode" => {
+                    if let Some(&mode) = GameMode::ALL.iter().find(|m| m.name() == value) {
+                        self.mode = mode;
+                    }
+                }
+                "randomizer" => {
+                    if let Some(&kind) = Randomizer::ALL.iter().find(|r| r.name() == value) {
+                        self.piece_gen.kind = kind;
+                    }
+                }
+                "reduced_motion" => parse_into(&mut self.reduced_motion, value),
+                "music" => parse_into(&mut self.music_enabled, value),
+                "music_volume" => {
+                    if let Ok(volume) = value.parse() {
+                        self.mus_mgr.set_volume(volume);
+                    }
+                }
+                "lock_out" => parse_into(&mut self.lock_out_enabled, value),
+                "hold" => parse_into(&mut self.hold_enabled, value),
+                "hold_limit" => {
+                    let limit = if value == "unlimited" { None } else { value.parse().ok() };
+                    if HOLD_LIMIT_OPTIONS.contains(&limit) {
+                        self.hold_limit = limit;
+                    }
+                }
+                "hold_costs_time" => parse_into(&mut self.hold_costs_time, value),
+                "next_pieces" => {
+                    if let Ok(count) = value.parse::<usize>() {
+                        self.next_preview_count = count.clamp(1, NEXT_QUEUE_LEN);
+                    }
+                }
+                "invisible" => parse_into(&mut self.invisible_mode, value),
+                "big_mode" => parse_into(&mut self.big_mode, value),
+                "spawn_rotations" => {
+                    let turns: Vec<u8> = value.split(',').filter_map(|t| t.trim().parse().ok()).collect();
+                    if turns.len() == self.spawn_rotations.len() && turns.iter().all(|&t| t < 4) {
+                        self.spawn_rotations.copy_from_slice(&turns);
+                    }
+                }
+                "drop_distance" => parse_into(&mut self.show_drop_distance, value),
+                "finesse" => parse_into(&mut self.finesse_training, value),
+                "fill_gauge" => parse_into(&mut self.show_fill_gauge, value),
+                "rookie_assist" => parse_into(&mut self.rookie_assist, value),
+                "ghost_clears" => parse_into(&mut self.ghost_clear_preview, value),
+                "second_chance" => parse_into(&mut self.second_chance_assist, value),
+                "game_log" => parse_into(&mut self.game_log_enabled, value),
+                "block_style" => {
+                    if let Some(&style) = BlockStyle::ALL.iter().find(|s| s.name() == value) {
+                        self.block_style = style;
+                    }
+                }
+                "block_outline" => parse_into(&mut self.block_outline, value),
+                "grid_lines" => parse_into(&mut self.show_grid_lines, value),
+                "controls_hud" => parse_into(&mut self.show_controls_hud, value),
+                "layout" => {
+                    if let Some(&layout) = Layout::ALL.iter().find(|l| l.name() == value) {
+                        self.layout = layout;
+                    }
+                }
+                key => {
+                    let piece = TETROMINOES.iter().position(|&t| color_key(t) == key);
+                    let rgb: Vec<f32> = value.split(',').filter_map(|c| c.trim().parse().ok()).collect();
+                    if let (Some(i), &[r, g, b]) = (piece, rgb.as_slice()) {
+                        self.piece_colors[i] = [r, g, b].map(|c| c.clamp(0.0, 1.0));
+                    }
+                }
+            }
+        }
+    }
+
+    // Loads the settings file, keeping the defaults if there isn't one yet.
+    fn load_settings(&mut self) {
+        let Some(path) = self.settings_path else { return };
+        match std::fs::read_to_string(path) {
+            Ok(text) => self.apply_settings_text(&text),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => eprintln!("Could not read {}: {}", path, err),
+        }
+    }
+
+    pub fn save_settings(&self) {
+        let Some(path) = self.settings_path else { return };
+        // Losing a settings change shouldn't interrupt play.
+        if let Err(err) = replace_file(path, &self.settings_text()) {
+            eprintln!("Could not write {}: {}", path, err);
+        }
+    }
+
+    // The settings overlay pauses play while it is open. Closing it goes
+    // back to whatever pause state the player left, so a game they paused
+    // themselves stays paused.
+    pub fn toggle_settings_overlay(&mut self) {
+        if self.show_settings_overlay {
+            self.show_settings_overlay = false;
+            self.set_paused(self.paused_under_settings);
+            self.save_settings();
+        } else {
+            self.paused_under_settings = self.paused;
+            self.show_settings_overlay = true;
+            self.set_paused(true);
+        }
+    }
+
+    // Drives whichever options list is on screen: the start menu, the piece
+    // color screen, or the in-game settings overlay while it is open.
+    pub fn process_menu_input(&mut self) {
+        let (items, index): (&[MenuItem], &mut usize) = if self.show_settings_overlay {
+            (&MenuItem::IN_GAME, &mut self.settings_index)
+        } else if self.show_color_editor {
+            (&MenuItem::COLOR_EDITOR, &mut self.color_menu_index)
+        } else {
+            (&MenuItem::ALL, &mut self.menu_index)
+        };
+        let len = items.len();
+        if is_key_pressed(KeyCode::Up) {
+            *index = (*index + len - 1) % len;
+        }
+        if is_key_pressed(KeyCode::Down) {
+            *index = (*index + 1) % len;
+        }
+        let step = if is_key_pressed(KeyCode::Left) {
+            -1
+        } else if is_key_pressed(KeyCode::Right) {
+            1
+        } else {
+            return;
+        };
+        let item = items[*index];
+        self.adjust_setting(item, step);
+    }
+
+    fn adjust_setting(&mut self, item: MenuItem, step: i32) {
+        match item {
+            MenuItem::Mode => self.mode = self.mode.cycle(step),
+            MenuItem::Randomizer => self.piece_gen.kind = self.piece_gen.kind.cycle(step),
+            MenuItem::ReducedMotion => self.reduced_motion = !self.reduced_motion,
+            MenuItem::Music => self.toggle_music(),
+            MenuItem::MusicVolume => {
+                let volume = self.mus_mgr.volume + step as f32 * MUSIC_VOLUME_STEP;
+                self.mus_mgr.set_volume((volume / MUSIC_VOLUME_STEP).round() * MUSIC_VOLUME_STEP);
+            }
+            MenuItem::LockOut => self.lock_out_enabled = !self.lock_out_enabled,
+            MenuItem::Invisible => self.invisible_mode = !self.invisible_mode,
+            MenuItem::BigMode => self.big_mode = !self.big_mode,
+            MenuItem::SpawnPiece => {
+                let set = self.mode.piece_set();
+                let len = set.len() as i32;
+                let index = set.iter().position(|&t| t == self.spawn_edit_type).unwrap_or(0) as i32;
+                self.spawn_edit_type = set[(index + step).rem_euclid(len) as usize];
+            }
+            MenuItem::SpawnRotation => {
+                let turns = &mut self.spawn_rotations[self.spawn_edit_type as usize];
+                *turns = (*turns as i32 + step).rem_euclid(4) as u8;
+            }
+            MenuItem::DropDistance => self.show_drop_distance = !self.show_drop_distance,
+            MenuItem::Finesse => self.finesse_training = !self.finesse_training,
+            MenuItem::FillGauge => self.show_fill_gauge = !self.show_fill_gauge,
+            MenuItem::RookieAssist => self.rookie_assist = !self.rookie_assist,
+            MenuItem::GhostClears => self.ghost_clear_preview = !self.ghost_clear_preview,
+            MenuItem::SecondChance => self.second_chance_assist = !self.second_chance_assist,
+            MenuItem::GameLog => self.game_log_enabled = !self.game_log_enabled,
+            MenuItem::NextCount => {
+                let count = self.next_preview_count as i32 - 1 + step;
+                self.next_preview_count = count.rem_euclid(NEXT_QUEUE_LEN as i32) as usize + 1;
+            }
+            MenuItem::Hold => self.hold_enabled = !self.hold_enabled,
+            MenuItem::HoldCostsTime => self.hold_costs_time = !self.hold_costs_time,
+            MenuItem::HoldLimit => {
+                let len = HOLD_LIMIT_OPTIONS.len() as i32;
+                let index = HOLD_LIMIT_OPTIONS.iter().position(|&l| l == self.hold_limit).unwrap_or(0) as i32;
+                self.hold_limit = HOLD_LIMIT_OPTIONS[(index + step).rem_euclid(len) as usize];
+            }
+            MenuItem::BlockStyle => self.block_style = self.block_style.toggle(),
+            MenuItem::BlockOutline => self.block_outline = !self.block_outline,
+            MenuItem::GridLines => self.show_grid_lines = !self.show_grid_lines,
+            MenuItem::PieceColors => self.show_color_editor = true,
+            MenuItem::ColorPiece => {
+                let len = TETROMINOES.len() as i32;
+                self.color_edit_index = (self.color_edit_index as i32 + step).rem_euclid(len) as usize;
+            }
+            MenuItem::ColorRed | MenuItem::ColorGreen | MenuItem::ColorBlue => {
+                let channel = &mut self.piece_colors[self.color_edit_index][color_channel(item)];
+                let value = *channel + step as f32 * COLOR_CHANNEL_STEP;
+                *channel = ((value / COLOR_CHANNEL_STEP).round() * COLOR_CHANNEL_STEP).clamp(0.0, 1.0);
+            }
+            MenuItem::ResetColors => self.piece_colors = nes_piece_colors(),
+            MenuItem::ControlsHud => self.show_controls_hud = !self.show_controls_hud,
+        }
+    }
+
+    // Music on/off. Mid-game this mutes or unmutes straight away; either
+    // way it carries over to the next game.
+    fn toggle_music(&mut self) {
+        self.music_enabled = !self.music_enabled;
+        if self.started {
+            self.mus_mgr.set_muted(!self.music_enabled);
+        }
+    }
+
+    fn menu_label(&self, item: MenuItem) -> String {
+        match item {
+            MenuItem::Mode => format!("Mode: {}", self.mode.name()),
+            MenuItem::Randomizer => format!("Randomizer: {}", self.piece_gen.kind.name()),
+            MenuItem::ReducedMotion => format!("Reduced Motion: {}", on_off(self.reduced_motion)),
+            MenuItem::Music => format!("Music: {}", on_off(self.music_enabled)),
+            MenuItem::MusicVolume => format!("Music Volume: {:.0}%", self.mus_mgr.volume * 100.0),
+            MenuItem::LockOut => format!("Lock Out: {}", on_off(self.lock_out_enabled)),
+            MenuItem::Invisible => format!("Invisible Stack: {}", on_off(self.invisible_mode)),
+            MenuItem::BigMode => format!("Big Mode (novelty): {}", on_off(self.big_mode)),
+            MenuItem::SpawnPiece => format!("Spawn Rotation Piece: {:?}", self.spawn_edit_type),
+            MenuItem::SpawnRotation => format!(
+                "{:?} Spawns: {}",
+                self.spawn_edit_type,
+                SPAWN_ROTATION_NAMES[self.spawn_rotations[self.spawn_edit_type as usize] as usize]
+            ),
+            MenuItem::DropDistance => format!("Drop Distance: {}", on_off(self.show_drop_distance)),
+            MenuItem::Finesse => format!("Finesse Training: {}", on_off(self.finesse_training)),
+            MenuItem::FillGauge => format!("Fill Gauge: {}", on_off(self.show_fill_gauge)),
+            MenuItem::RookieAssist => format!("Rookie Assist: {}", on_off(self.rookie_assist)),
+            MenuItem::GhostClears => format!("Ghost Shows Line Clears: {}", on_off(self.ghost_clear_preview)),
+            MenuItem::SecondChance => format!("Second Chance: {}", on_off(self.second_chance_assist)),
+            MenuItem::GameLog => format!("Log Games to CSV: {}", on_off(self.game_log_enabled)),
+            MenuItem::NextCount => format!("Next Pieces: {}", self.next_preview_count),
+            MenuItem::Hold => format!("Hold: {}", on_off(self.hold_enabled)),
+            MenuItem::HoldCostsTime => format!("Ultra Hold Costs {}s: {}", HOLD_TIME_COST, on_off(self.hold_costs_time)),
+            MenuItem::HoldLimit => match self.hold_limit {
+                Some(limit) => format!("Hold Limit: {}", limit),
+                None => "Hold Limit: Unlimited".to_string(),
+            },
+            MenuItem::BlockStyle => format!("Blocks: {}", self.block_style.name()),
+            MenuItem::BlockOutline => format!("Block Outlines: {}", on_off(self.block_outline)),
+            MenuItem::GridLines => format!("Grid Lines: {}", on_off(self.show_grid_lines)),
+            MenuItem::PieceColors if self.piece_colors == nes_piece_colors() => "Piece Colors: NES (Right to edit)".to_string(),
+            MenuItem::PieceColors => "Piece Colors: Custom (Right to edit)".to_string(),
+            MenuItem::ColorPiece => format!("Piece: {:?}", TETROMINOES[self.color_edit_index]),
+            MenuItem::ColorRed | MenuItem::ColorGreen | MenuItem::ColorBlue => format!(
+                "{}: {:.0}%",
+                ["Red", "Green", "Blue"][color_channel(item)],
+                self.piece_colors[self.color_edit_index][color_channel(item)] * 100.0
+            ),
+            MenuItem::ResetColors => "Reset All to NES Colors".to_string(),
+            MenuItem::ControlsHud => format!("Controls on HUD: {}", on_off(self.show_controls_hud)),
         }
     }
 
     pub fn update(&mut self) {
         let dt = get_frame_time();
-        if !self.game_over && is_key_pressed(KeyCode::Enter) {
-            self.paused = !self.paused;
-            self.mus_mgr.pause();
+        // The latency test takes over the start screen until Esc.
+        if let Some(calibration) = self.calibration.as_mut() {
+            calibration.update();
+            if is_key_pressed(KeyCode::Escape) {
+                self.calibration = None;
+            }
+            return;
+        }
+        if !self.started && !self.game_over {
+            if self.show_color_editor {
+                if is_key_pressed(KeyCode::Escape) {
+                    self.show_color_editor = false;
+                    self.save_settings();
+                }
+            } else if is_key_pressed(KeyCode::L) {
+                self.calibration = Some(LatencyCalibration::new());
+                return;
+            }
+            self.process_menu_input();
+        }
+        if self.started && !self.game_over && !self.show_settings_overlay && is_key_pressed(KeyCode::Enter) {
+            self.set_paused(!self.paused);
+        }
+        if self.started && !self.game_over && is_key_pressed(KeyCode::O) {
+            self.toggle_settings_overlay();
+        } else if self.show_settings_overlay {
+            self.process_menu_input();
         }
+        // The help overlay is purely visual; gameplay keeps running under it.
+        if self.started && is_key_pressed(KeyCode::H) {
+            self.show_controls_overlay = !self.show_controls_overlay;
+        }
+        if is_key_pressed(KeyCode::F2) {
+            self.layout = self.layout.toggle();
+        }
+        if is_key_pressed(KeyCode::F3) {
+            self.show_debug_overlay = !self.show_debug_overlay;
+        }
+        if self.game_over && self.game_over_anim_timer > 0.0 {
+            self.game_over_anim_timer = (self.game_over_anim_timer - dt).max(0.0);
+        }
+        self.advance_play_clock(dt as f64);
         if self.paused || !self.started || self.game_over {
             return;
         }
+        self.update_drop_trails(dt);
+        self.update_lock_flashes(dt);
+        if let Some((_, timer)) = self.popup.as_mut() {
+            *timer -= dt;
+            if *timer <= 0.0 {
+                self.popup = None;
+            }
+        }
+        self.update_danger_level(dt);
+        self.update_music_tempo(dt);
+        if self.mode == GameMode::Ultra {
+            self.time_remaining = self.ultra_time_left();
+            if self.time_remaining <= 0.0 {
+                self.end_game();
+                return;
+            }
+            // Peaks as each of the last seconds ticks over, then fades.
+            self.timer_pulse = if self.time_remaining < LOW_TIME_WARNING {
+                self.time_remaining.fract()
+            } else {
+                0.0
+            };
+        }
         if self.line_clear_timer > 0.0 {
+            // Duck the music under the clear animation.
+            self.mus_mgr.set_ducked(true);
             self.line_clear_timer -= dt;
             if self.line_clear_timer <= 0.0 {
+                self.mus_mgr.set_ducked(false);
                 self.clear_lines_delayed();
             }
             return;
         }
+        if self.square_freeze(dt) {
+            return;
+        }
         self.process_input(dt);
-        if let Some(curr) = self.tetromino {
-            let speed = if is_key_down(KeyCode::Down) { SOFT_DROP_SPEED } else { FALL_SPEED };
-            let fall_interval = 1.0 / speed;
+        self.apply_gravity(dt, is_key_down(KeyCode::Down));
+        self.update_square_effects(dt);
+    }
+
+    // Holds the active piece still while a bonus square blinks, so nothing
+    // lands on it mid-effect; capped in case the effect never resolves.
+    // Returns whether this frame is frozen, in which case only the blink
+    // advances.
+    pub fn square_freeze(&mut self, dt: f32) -> bool {
+        if self.active_squares.is_empty() {
+            self.square_freeze_timer = 0.0;
+            return false;
+        }
+        if self.square_freeze_timer >= SQUARE_FREEZE_MAX {
+            return false;
+        }
+        self.square_freeze_timer += dt;
+        self.update_square_effects(dt);
+        true
+    }
+
+    // Moves the active piece down for `dt` seconds of gravity, or of soft
+    // drop while `soft_drop` is held, locking it when it lands.
+    pub fn apply_gravity(&mut self, dt: f32, soft_drop: bool) {
+        if self.waiting_for_input {
+            self.input_wait_timer += dt;
+            if self.input_wait_timer >= ROOKIE_WAIT_TIMEOUT {
+                self.waiting_for_input = false;
+            }
+        } else if self.tetromino.is_some() {
+            let fall_interval = self.fall_interval(soft_drop);
             self.fall_timer += dt;
-            if self.fall_timer >= fall_interval {
+            // One step per elapsed interval, however long the frame was, so
+            // the drop rate doesn't depend on the frame rate.
+            while self.fall_timer >= fall_interval {
                 self.fall_timer -= fall_interval;
+                let Some(curr) = self.tetromino else { break };
                 if self.check_collision(&curr.shape, (curr.pos.0, curr.pos.1 + 1)) {
                     self.lock_tetromino();
-                } else {
-                    self.move_tetromino((0, 1));
+                    break;
+                }
+                self.move_tetromino((0, 1));
+                if soft_drop {
+                    self.score += 1;
                 }
             }
         }
-        self.update_square_effects(dt);
+    }
+
+    // Seconds between gravity steps for the current mode, or while soft
+    // dropping.
+    pub fn fall_interval(&self, soft_drop: bool) -> f32 {
+        let base_speed = if self.mode == GameMode::Zen { ZEN_FALL_SPEED } else { FALL_SPEED };
+        let speed = if soft_drop { SOFT_DROP_SPEED } else { base_speed };
+        1.0 / speed
     }
 
     pub fn draw(&mut self) {
         clear_background(BLACK_COLOR);
 
+        if let Some(calibration) = &self.calibration {
+            calibration.draw();
+            return;
+        }
+
         // If the game hasn't started, show "Press SPACE to start"
-        if !self.started {
+        if !self.started && !self.game_over {
             self.mus_mgr.reset();
+            if self.show_color_editor {
+                self.draw_color_editor();
+                return;
+            }
             let msg = "Press SPACE to start";
+            // Center the prompt and the option rows together.
+            // Rows shrink a little once the options outgrow the window.
+            let row_h = ((screen_height() - 100.0) / MenuItem::ALL.len() as f32).min(30.0);
+            let font = row_h - 2.0;
+            let block_h = 50.0 + MenuItem::ALL.len() as f32 * row_h;
             let measure = measure_text(msg, None, 40, 1.0);
             let x = (screen_width() - measure.width) / 2.0;
-            let y = (screen_height() - measure.height) / 2.0;
+            let y = (screen_height() - block_h) / 2.0 + measure.height;
             draw_text(msg, x, y, 40.0, YELLOW);
+
+            // Options menu: Up/Down selects a row, Left/Right changes it.
+            for (i, &item) in MenuItem::ALL.iter().enumerate() {
+                let selected = i == self.menu_index;
+                let label = if selected {
+                    format!("< {} >", self.menu_label(item))
+                } else {
+                    self.menu_label(item)
+                };
+                let measure = measure_text(&label, None, font as u16, 1.0);
+                let x = (screen_width() - measure.width) / 2.0;
+                let color = if selected { WHITE } else { GRAY };
+                draw_text(&label, x, y + 50.0 + i as f32 * row_h, font, color);
+            }
+            draw_text("L: Input latency test", 20.0, screen_height() - 20.0, 22.0, GRAY);
             return;
         }
 
         // Draw the main board background
         let board_w = GRID_WIDTH as f32 * TILE_SIZE;
-        let board_h = GRID_HEIGHT as f32 * TILE_SIZE;
-        let offset_x = (screen_width() - board_w) / 2.0;
-        let offset_y = (screen_height() - board_h) / 2.0 - 50.0;
+        let board_h = VISIBLE_ROWS as f32 * TILE_SIZE;
+        let (offset_x, offset_y) = self.layout.board_origin(board_w, board_h);
         draw_rectangle(offset_x, offset_y, board_w, board_h, GAME_AREA_COLOR);
+        if self.show_grid_lines {
+            for x in 1..GRID_WIDTH {
+                let px = offset_x + x as f32 * TILE_SIZE;
+                draw_line(px, offset_y, px, offset_y + board_h, 1.0, GRID_LINE_COLOR);
+            }
+            for y in 1..VISIBLE_ROWS {
+                let py = offset_y + y as f32 * TILE_SIZE;
+                draw_line(offset_x, py, offset_x + board_w, py, 1.0, GRID_LINE_COLOR);
+            }
+        }
 
-        // Draw locked pieces on the board
-        for y in 0..GRID_HEIGHT {
+        // Draw locked pieces on the board (buffer rows are skipped)
+        let now = get_time();
+        for y in HIDDEN_ROWS..GRID_HEIGHT {
             for x in 0..GRID_WIDTH {
-                if let Some((color, _t, _id)) = self.board[y][x] {
+                if let Some((color, _t, id)) = self.board[y][x] {
+                    // Invisible mode hides settled cells; the board still exists.
+                    // The stack is revealed again on the game-over screen.
+                    if !self.game_over && self.visible_until.get(&id).is_some_and(|&until| now >= until) {
+                        continue;
+                    }
                     let mut draw_color = color;
+                    // Freshly locked cells start white and settle into their color.
+                    if let Some(&(_, timer)) = self.recently_locked.iter().find(|&&(pid, _)| pid == id) {
+                        draw_color = lerp_color(color, WHITE, timer / LOCK_FLASH_DURATION);
+                    }
                     // If it's in an active 4x4 square effect, apply the blinking effect
                     for eff in &self.active_squares {
                         if x >= eff.x && x < eff.x + 4 && y >= eff.y && y < eff.y + 4 {
@@ -716,62 +2372,160 @@ impl GameState {
                         }
                     }
                     let px = offset_x + x as f32 * TILE_SIZE;
-                    let py = offset_y + y as f32 * TILE_SIZE;
-                    draw_snes_block(px, py, TILE_SIZE, draw_color);
+                    let py = offset_y + (y - HIDDEN_ROWS) as f32 * TILE_SIZE;
+                    draw_snes_block(px, py, TILE_SIZE, draw_color, self.block_style, self.block_outline);
                 }
             }
         }
 
+        // Draw hard-drop trails, fading with age and toward the top of the streak.
+        for trail in &self.drop_trails {
+            let fade = trail.timer / DROP_TRAIL_DURATION;
+            for &(x, y) in &trail.cells {
+                let Some(py) = visible_row_y(offset_y, y) else { continue };
+                let distance = (trail.end_y - y) as f32;
+                let alpha = 0.35 * fade / (1.0 + distance * 0.25);
+                let color = Color::new(trail.color.r, trail.color.g, trail.color.b, alpha);
+                let px = offset_x + x as f32 * TILE_SIZE;
+                draw_rectangle(px, py, TILE_SIZE, TILE_SIZE, color);
+            }
+        }
+
         // Draw the "ghost" piece (projection)
-        if let Some(curr) = self.tetromino {
+        if let (Some(curr), Some(ghost_y)) = (self.tetromino, self.ghost_y()) {
             let mut ghost = curr;
-            let mut iter = 0;
-            while !self.check_collision(&ghost.shape, (ghost.pos.0, ghost.pos.1 + 1)) && iter < 100 {
-                ghost.pos.1 += 1;
-                iter += 1;
-            }
+            ghost.pos.1 = ghost_y;
             let ghost_color = Color::new(curr.color.r, curr.color.g, curr.color.b, 0.3);
-            for &[dx, dy] in &ghost.shape {
-                let x = ghost.pos.0 + dx;
-                let y = ghost.pos.1 + dy;
+            // Rows the ghost placement would clear get a faint wash.
+            if self.ghost_clear_preview {
+                for y in self.rows_completed_by(&ghost.shape, ghost.pos) {
+                    let Some(py) = visible_row_y(offset_y, y) else { continue };
+                    draw_rectangle(offset_x, py, board_w, TILE_SIZE, Color::new(1.0, 1.0, 1.0, 0.15));
+                }
+            }
+            for (x, y) in self.piece_cells(&ghost.shape, ghost.pos) {
+                let Some(py) = visible_row_y(offset_y, y) else { continue };
                 let px = offset_x + x as f32 * TILE_SIZE;
-                let py = offset_y + y as f32 * TILE_SIZE;
                 draw_rectangle(px, py, TILE_SIZE, TILE_SIZE, ghost_color);
             }
 
             // Draw the active falling piece
-            for &[dx, dy] in &curr.shape {
-                let x = curr.pos.0 + dx;
-                let y = curr.pos.1 + dy;
+            let cells = self.piece_cells(&curr.shape, curr.pos);
+            for &(x, y) in &cells {
+                let Some(py) = visible_row_y(offset_y, y) else { continue };
                 let px = offset_x + x as f32 * TILE_SIZE;
-                let py = offset_y + y as f32 * TILE_SIZE;
-                draw_snes_block(px, py, TILE_SIZE, curr.color);
+                draw_snes_block(px, py, TILE_SIZE, curr.color, self.block_style, self.block_outline);
+            }
+
+            // Rows a hard drop would travel, next to the piece's right edge.
+            if self.show_drop_distance {
+                let right = cells.iter().map(|&(x, _)| x).max().unwrap_or(0);
+                let top = cells.iter().map(|&(_, y)| y).min().unwrap_or(0);
+                let row = top.max(HIDDEN_ROWS as i32);
+                if let Some(py) = visible_row_y(offset_y, row) {
+                    let px = offset_x + (right + 1) as f32 * TILE_SIZE + 4.0;
+                    let distance = ghost_y - curr.pos.1;
+                    draw_text(&distance.to_string(), px, py + TILE_SIZE * 0.7, 22.0, WHITE);
+                }
+            }
+        }
+
+        // Tint the top rows red while the stack is close to topping out.
+        if self.danger_level > 0.0 {
+            let pulse = if self.reduced_motion {
+                0.6
+            } else {
+                0.45 + 0.3 * (get_time() as f32 * 6.0).sin()
+            };
+            let alpha = 0.35 * self.danger_level * pulse;
+            draw_rectangle(offset_x, offset_y, board_w, TILE_SIZE * DANGER_ROWS as f32, Color::new(1.0, 0.0, 0.0, alpha));
+        }
+
+        // Fill gauge: a thin bar left of the board, green through yellow to red.
+        if self.show_fill_gauge {
+            let fill = self.board_fill();
+            let color = if fill < 0.5 {
+                lerp_color(GREEN, YELLOW, fill * 2.0)
+            } else {
+                lerp_color(YELLOW, RED, (fill - 0.5) * 2.0)
+            };
+            let bar_x = offset_x - 14.0;
+            draw_rectangle(bar_x, offset_y, 6.0, board_h, GAME_AREA_COLOR);
+            draw_rectangle(bar_x, offset_y + board_h * (1.0 - fill), 6.0, board_h * fill, color);
+            if self.layout == Layout::Full {
+                let label = format!("{:.0}%", fill * 100.0);
+                let measure = measure_text(&label, None, 20, 1.0);
+                draw_text(&label, bar_x + 3.0 - measure.width / 2.0, offset_y + board_h + 20.0, 20.0, WHITE);
             }
         }
 
         // If lines are clearing, flash them
-        draw_rectangle(offset_x, offset_y, board_w, TILE_SIZE * 2.0, BLACK_COLOR);
         if self.line_clear_timer > 0.0 {
             let frames = (self.line_clear_timer * 60.0) as i32;
             let flash_on = frames % 2 == 0;
-            let flash_color = if flash_on { WHITE } else { BLACK_COLOR };
+            let flash_color = if self.reduced_motion {
+                // A steady dim instead of strobing white.
+                Color::new(0.0, 0.0, 0.0, 0.6)
+            } else if flash_on {
+                WHITE
+            } else {
+                BLACK_COLOR
+            };
             for &row in &self.clearing_lines {
-                let py = offset_y + row as f32 * TILE_SIZE;
+                let Some(py) = visible_row_y(offset_y, row as i32) else { continue };
                 draw_rectangle(offset_x, py, board_w, TILE_SIZE, flash_color);
             }
         }
 
-        // Lines and Score on the right side
-        draw_text(&format!("Lines: {}", self.lines_cleared), screen_width() - 210.0, 170.0, 40.0, WHITE);
-        draw_text(&format!("Score: {}", self.score), screen_width() - 210.0, 220.0, 40.0, WHITE);
+        if let Some((msg, timer)) = &self.popup {
+            let alpha = if self.reduced_motion { 1.0 } else { (timer / POPUP_DURATION * 2.0).min(1.0) };
+            let measure = measure_text(msg, None, 40, 1.0);
+            let x = offset_x + (board_w - measure.width) / 2.0;
+            draw_text(msg, x, offset_y + board_h * 0.3, 40.0, Color::new(1.0, 1.0, 0.0, alpha));
+        }
+
+        match self.layout {
+            Layout::Full => self.draw_full_panels(offset_x, offset_y, board_w, board_h),
+            Layout::Compact => self.draw_compact_panels(offset_x, offset_y, board_w, board_h),
+        }
+
+        // Game-over curtain: fill the visible rows bottom-up with gray blocks.
+        if self.game_over && self.top_out_reason.is_some() && !self.reduced_motion {
+            let progress = 1.0 - self.game_over_anim_timer / GAME_OVER_ANIM_DURATION;
+            let filled = ((progress * VISIBLE_ROWS as f32).ceil() as usize).min(VISIBLE_ROWS);
+            for row in (VISIBLE_ROWS - filled)..VISIBLE_ROWS {
+                for x in 0..GRID_WIDTH {
+                    let px = offset_x + x as f32 * TILE_SIZE;
+                    let py = offset_y + row as f32 * TILE_SIZE;
+                    draw_snes_block(px, py, TILE_SIZE, CURTAIN_COLOR, self.block_style, self.block_outline);
+                }
+            }
+        }
 
-        // Game Over message
-        if self.game_over {
-            let msg = "Game Over";
+        // Game Over message, once the curtain has finished
+        if self.game_over && self.game_over_anim_timer <= 0.0 {
+            let time_up = self.mode == GameMode::Ultra && self.time_remaining <= 0.0;
+            let msg = if time_up { "Time Up" } else { "Game Over" };
             let measure = measure_text(msg, None, 50, 1.0);
             let x = offset_x + (board_w - measure.width) / 2.0;
             let y = offset_y + board_h / 2.0;
             draw_text(msg, x, y, 50.0, RED);
+
+            if let Some(reason) = self.top_out_reason {
+                let measure = measure_text(reason.name(), None, 28, 1.0);
+                draw_text(reason.name(), offset_x + (board_w - measure.width) / 2.0, y - 50.0, 28.0, ORANGE);
+            }
+            let score_msg = format!("Final Score: {}", self.score);
+            let measure = measure_text(&score_msg, None, 30, 1.0);
+            draw_text(&score_msg, offset_x + (board_w - measure.width) / 2.0, y + 40.0, 30.0, WHITE);
+            if self.finesse_training {
+                let faults_msg = format!("Finesse Faults: {}", self.finesse_faults);
+                let measure = measure_text(&faults_msg, None, 24, 1.0);
+                draw_text(&faults_msg, offset_x + (board_w - measure.width) / 2.0, y + 105.0, 24.0, WHITE);
+            }
+            let cont_msg = "Press SPACE to continue";
+            let measure = measure_text(cont_msg, None, 24, 1.0);
+            draw_text(cont_msg, offset_x + (board_w - measure.width) / 2.0, y + 75.0, 24.0, YELLOW);
         }
 
         // Pause overlay
@@ -782,12 +2536,52 @@ impl GameState {
             draw_text(msg, (screen_width()-measure.width)/2.0, screen_height()/2.0, 50.0, YELLOW);
         }
 
+        if self.show_settings_overlay {
+            self.draw_settings_overlay();
+        }
+
+        if self.show_controls_overlay {
+            draw_controls_overlay(&self.controls());
+        }
+
+        if self.show_debug_overlay {
+            self.draw_debug_overlay();
+        }
+    }
+
+    // Side panels of the full layout: Hold and piece stats on the left,
+    // Next and the score readouts on the right, controls text below.
+    fn draw_full_panels(&self, offset_x: f32, offset_y: f32, board_w: f32, board_h: f32) {
+        // Lines and Score on the right side
+        draw_text(&format!("Lines: {}", self.lines_cleared), screen_width() - 210.0, 170.0, 40.0, WHITE);
+        draw_text(&format!("Score: {}", self.score), screen_width() - 210.0, 220.0, 40.0, WHITE);
+        draw_text(&format!("Mode: {}", self.mode.name()), screen_width() - 210.0, 260.0, 30.0, WHITE);
+        if self.mode == GameMode::Ultra {
+            self.draw_ultra_time(screen_width() - 210.0, 300.0);
+        }
+        if self.finesse_training {
+            draw_text(&format!("Faults: {}", self.finesse_faults), screen_width() - 210.0, 340.0, 30.0, WHITE);
+        }
+
         // -- LEFT SIDE PANELS: Hold piece & Piece Stats --
 
         // Draw "Hold" text and hold piece preview
-        draw_text("Hold", 79.0, 55.0, 40.0, WHITE);
-        if let Some(ref hold_piece) = self.hold_tetromino {
-            draw_preview(hold_piece, 79.0, 90.0, PREVIEW_TILE_SIZE);
+        // Once a hold limit is used up, the panel is grayed out for the game.
+        let hold_exhausted = self.holds_left() == Some(0);
+        if self.hold_enabled {
+            draw_text("Hold", 79.0, 55.0, 40.0, if hold_exhausted { GRAY } else { WHITE });
+        } else {
+            draw_text("Hold", 79.0, 55.0, 40.0, GRAY);
+            draw_text("Disabled", 79.0, 120.0, 24.0, GRAY);
+        }
+        if let Some(mut hold_piece) = self.hold_tetromino {
+            if hold_exhausted {
+                hold_piece.color = lerp_color(hold_piece.color, GAME_AREA_COLOR, 0.7);
+            }
+            draw_preview(&hold_piece, 79.0, 90.0, PREVIEW_TILE_SIZE, self.block_style, self.block_outline);
+        }
+        if let Some(left) = self.holds_left().filter(|_| self.hold_enabled) {
+            draw_text(&format!("Holds left: {}", left), 79.0, 170.0, 24.0, if hold_exhausted { GRAY } else { WHITE });
         }
 
         // Draw the piece statistics under the hold piece
@@ -795,33 +2589,28 @@ impl GameState {
         let stats_label_y = 200.0;
         draw_text("Piece Stats", stats_label_x, stats_label_y, 30.0, WHITE);
 
-        let stat_types = [
-            TetrominoType::I,
-            TetrominoType::O,
-            TetrominoType::T,
-            TetrominoType::S,
-            TetrominoType::Z,
-            TetrominoType::J,
-            TetrominoType::L,
-        ];
+        let stat_types = self.mode.piece_set();
+        // Pentominoes spill into a second column and draw a little smaller.
+        let tile = if stat_types.len() > 7 { 12.0 } else { 15.0 };
 
         // Each piece gets a small preview plus its count
         for (i, &piece_type) in stat_types.iter().enumerate() {
-            let piece_y = stats_label_y + 40.0 + (i as f32 * 50.0);
+            let column_x = stats_label_x + (i / 7) as f32 * 110.0;
+            let piece_y = stats_label_y + 40.0 + ((i % 7) as f32 * 50.0);
             // Create a dummy tetromino just for drawing its shape
             let t = Tetromino {
                 shape: TETROMINO_SHAPES[piece_type as usize],
                 pos: (0, 0),
-                color: NES_COLORS[piece_type as usize],
+                color: self.piece_color(piece_type),
                 t_type: piece_type,
             };
             // Draw a small preview on the left
-            draw_preview(&t, stats_label_x, piece_y, 15.0);
+            draw_preview(&t, column_x, piece_y, tile, self.block_style, self.block_outline);
             // Show the count on the right
             let count = self.piece_statistics.get(&piece_type).unwrap_or(&0);
             draw_text(
                 &format!("{}", count),
-                stats_label_x + 50.0,
+                column_x + 50.0,
                 piece_y + 20.0,
                 20.0,
                 WHITE,
@@ -830,38 +2619,205 @@ impl GameState {
 
         // -- RIGHT SIDE: Next piece label & preview --
         draw_text("Next", screen_width() - 210.0, 55.0, 40.0, WHITE);
-        if let Some(ref next_piece) = self.next_tetromino {
-            draw_preview(next_piece, screen_width() - 218.0, 70.0, PREVIEW_TILE_SIZE);
-        }
-
-        // Controls text at the bottom
-        let controls_text = "\
-Controls:
- Left/Right: Move
- Up: Hard Drop
- Down: Soft Drop
- Z/X: Rotate
- C: Hold
- Enter: Pause
- Space: Start
- N: Change Song
- M: Mute Music";
-        let text_x = 20.0;
-        let text_y = offset_y + board_h + 80.0;
-        let wrapped = wrap_text(controls_text, screen_width() - 40.0, 24);
-        draw_text_ex(
-            &wrapped,
-            text_x,
-            text_y,
-            TextParams {
-                font: None,
-                font_size: 24,
-                font_scale: 1.0,
-                font_scale_aspect: 1.0,
-                rotation: 0.0,
-                color: WHITE,
-            },
-        );
+        let mut shown = self.next_queue.iter().take(self.next_preview_count);
+        if let Some(next_piece) = shown.next() {
+            draw_preview(next_piece, screen_width() - 218.0, 70.0, PREVIEW_TILE_SIZE, self.block_style, self.block_outline);
+        }
+        // Further queued pieces go in a smaller column beside the board.
+        let queue_x = offset_x + board_w + 40.0;
+        for (i, queued) in shown.enumerate() {
+            draw_preview(queued, queue_x, 70.0 + i as f32 * 60.0, QUEUE_TILE_SIZE, self.block_style, self.block_outline);
+        }
+
+        // Controls text at the bottom, only when enabled in the menu.
+        if self.show_controls_hud {
+            let mut controls_text = String::from("Controls:");
+            for (key, action) in self.controls() {
+                controls_text.push_str(&format!("\n {}: {}", key, action));
+            }
+            let text_x = 20.0;
+            let text_y = offset_y + board_h + 80.0;
+            let wrapped = wrap_text(&controls_text, screen_width() - 40.0, 24);
+            draw_text_ex(
+                &wrapped,
+                text_x,
+                text_y,
+                TextParams {
+                    font: None,
+                    font_size: 24,
+                    font_scale: 1.0,
+                    font_scale_aspect: 1.0,
+                    rotation: 0.0,
+                    color: WHITE,
+                },
+            );
+        } else {
+            draw_text("H: Controls", 20.0, offset_y + board_h + 80.0, 24.0, GRAY);
+        }
+    }
+
+    // Tight strip for stream overlays: Hold and Score above the board,
+    // the Next queue in a row below it.
+    fn draw_compact_panels(&self, offset_x: f32, offset_y: f32, board_w: f32, board_h: f32) {
+        let hold_exhausted = self.holds_left() == Some(0);
+        if let Some(mut hold_piece) = self.hold_tetromino {
+            if hold_exhausted {
+                hold_piece.color = lerp_color(hold_piece.color, GAME_AREA_COLOR, 0.7);
+            }
+            draw_preview(&hold_piece, offset_x, 0.0, COMPACT_TILE_SIZE, self.block_style, self.block_outline);
+        }
+        if self.mode == GameMode::Ultra {
+            self.draw_ultra_time(offset_x + 70.0, 35.0);
+        }
+        let score_text = self.score.to_string();
+        let measure = measure_text(&score_text, None, 30, 1.0);
+        draw_text(&score_text, offset_x + board_w - measure.width, 35.0, 30.0, WHITE);
+
+        for (i, queued) in self.next_queue.iter().take(self.next_preview_count).enumerate() {
+            draw_preview(queued, offset_x + i as f32 * 60.0, offset_y + board_h, COMPACT_TILE_SIZE, self.block_style, self.block_outline);
+        }
+    }
+
+    // Ultra countdown, pulsing red through the last seconds.
+    fn draw_ultra_time(&self, x: f32, y: f32) {
+        let secs = self.time_remaining.ceil() as u32;
+        let time_text = format!("Time: {}:{:02}", secs / 60, secs % 60);
+        let (time_color, time_size) = if self.time_remaining >= LOW_TIME_WARNING {
+            (WHITE, 30.0)
+        } else if self.reduced_motion {
+            (RED, 30.0)
+        } else {
+            (lerp_color(Color::new(0.6, 0.0, 0.0, 1.0), RED, self.timer_pulse), 30.0 + 6.0 * self.timer_pulse)
+        };
+        draw_text(&time_text, x, y, time_size, time_color);
+    }
+
+    fn draw_settings_overlay(&self) {
+        let line_h = 34.0;
+        let box_w = 420.0;
+        let box_h = 110.0 + MenuItem::IN_GAME.len() as f32 * line_h;
+        let box_x = (screen_width() - box_w) / 2.0;
+        let box_y = (screen_height() - box_h) / 2.0;
+        draw_rectangle(box_x, box_y, box_w, box_h, Color::new(0.0, 0.0, 0.0, 0.9));
+        draw_rectangle_lines(box_x, box_y, box_w, box_h, 2.0, WHITE);
+
+        let title = "Settings";
+        let measure = measure_text(title, None, 36, 1.0);
+        draw_text(title, box_x + (box_w - measure.width) / 2.0, box_y + 40.0, 36.0, YELLOW);
+        for (i, &item) in MenuItem::IN_GAME.iter().enumerate() {
+            let selected = i == self.settings_index;
+            let label = if selected {
+                format!("< {} >", self.menu_label(item))
+            } else {
+                self.menu_label(item)
+            };
+            let measure = measure_text(&label, None, 26, 1.0);
+            let color = if selected { WHITE } else { GRAY };
+            draw_text(&label, box_x + (box_w - measure.width) / 2.0, box_y + 80.0 + i as f32 * line_h, 26.0, color);
+        }
+        let hint = "O: Close";
+        let measure = measure_text(hint, None, 22, 1.0);
+        draw_text(hint, box_x + (box_w - measure.width) / 2.0, box_y + box_h - 15.0, 22.0, GRAY);
+    }
+
+    // The piece color screen: its rows and a preview of the piece being
+    // edited.
+    fn draw_color_editor(&self) {
+        let line_h = 34.0;
+        let box_w = 420.0;
+        let box_h = 190.0 + MenuItem::COLOR_EDITOR.len() as f32 * line_h;
+        let box_x = (screen_width() - box_w) / 2.0;
+        let box_y = (screen_height() - box_h) / 2.0;
+        draw_rectangle_lines(box_x, box_y, box_w, box_h, 2.0, WHITE);
+
+        let title = "Piece Colors";
+        let measure = measure_text(title, None, 36, 1.0);
+        draw_text(title, box_x + (box_w - measure.width) / 2.0, box_y + 40.0, 36.0, YELLOW);
+        let t_type = TETROMINOES[self.color_edit_index];
+        let preview = Tetromino {
+            shape: TETROMINO_SHAPES[t_type as usize],
+            pos: (0, 0),
+            color: self.piece_color(t_type),
+            t_type,
+        };
+        draw_preview(&preview, box_x + box_w / 2.0 - 25.0, box_y + 60.0, PREVIEW_TILE_SIZE, self.block_style, self.block_outline);
+        let rows_y = box_y + 160.0;
+        for (i, &item) in MenuItem::COLOR_EDITOR.iter().enumerate() {
+            let selected = i == self.color_menu_index;
+            let label = if selected {
+                format!("< {} >", self.menu_label(item))
+            } else {
+                self.menu_label(item)
+            };
+            let measure = measure_text(&label, None, 26, 1.0);
+            let color = if selected { WHITE } else { GRAY };
+            draw_text(&label, box_x + (box_w - measure.width) / 2.0, rows_y + i as f32 * line_h, 26.0, color);
+        }
+        let hint = "Esc: Back";
+        let measure = measure_text(hint, None, 22, 1.0);
+        draw_text(hint, box_x + (box_w - measure.width) / 2.0, box_y + box_h - 15.0, 22.0, GRAY);
+    }
+
+    fn draw_debug_overlay(&self) {
+        let pieces: u32 = self.piece_statistics.values().sum();
+        let lines = [
+            format!("FPS:      {}", get_fps()),
+            format!("dt:       {:.4}", get_frame_time()),
+            format!("pieces:   {}", pieces),
+            format!("squares:  {}", self.active_squares.len()),
+            format!("gravity:  {:.3}s", self.fall_interval(is_key_down(KeyCode::Down))),
+            format!("played:   {:.1}s", self.effective_play_time()),
+        ];
+        // Bottom-right corner, clear of the HUD panels.
+        let line_h = 18.0;
+        let box_w = 190.0;
+        let box_h = 10.0 + lines.len() as f32 * line_h;
+        let box_x = screen_width() - box_w;
+        let box_y = screen_height() - box_h;
+        draw_rectangle(box_x, box_y, box_w, box_h, Color::new(0.0, 0.0, 0.0, 0.7));
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, box_x + 8.0, box_y + 20.0 + i as f32 * line_h, 20.0, GREEN);
+        }
+    }
+}
+
+// Screen y of a board row, or None for the hidden buffer rows.
+fn visible_row_y(offset_y: f32, row: i32) -> Option<f32> {
+    if row < HIDDEN_ROWS as i32 {
+        return None;
+    }
+    Some(offset_y + (row - HIDDEN_ROWS as i32) as f32 * TILE_SIZE)
+}
+
+fn lerp_color(from: Color, to: Color, t: f32) -> Color {
+    Color::new(
+        from.r + (to.r - from.r) * t,
+        from.g + (to.g - from.g) * t,
+        from.b + (to.b - from.b) * t,
+        from.a + (to.a - from.a) * t,
+    )
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "On" } else { "Off" }
+}
+
+fn draw_controls_overlay(controls: &[(&str, &str)]) {
+    let line_h = 30.0;
+    let box_w = 460.0;
+    let box_h = 70.0 + controls.len() as f32 * line_h;
+    let box_x = (screen_width() - box_w) / 2.0;
+    let box_y = (screen_height() - box_h) / 2.0;
+    draw_rectangle(box_x, box_y, box_w, box_h, Color::new(0.0, 0.0, 0.0, 0.8));
+    draw_rectangle_lines(box_x, box_y, box_w, box_h, 2.0, WHITE);
+
+    let title = "Controls";
+    let measure = measure_text(title, None, 36, 1.0);
+    draw_text(title, box_x + (box_w - measure.width) / 2.0, box_y + 40.0, 36.0, YELLOW);
+    for (i, (key, action)) in controls.iter().enumerate() {
+        let y = box_y + 80.0 + i as f32 * line_h;
+        draw_text(key, box_x + 20.0, y, 26.0, WHITE);
+        draw_text(action, box_x + 260.0, y, 26.0, WHITE);
     }
 }
 
@@ -895,8 +2851,7 @@ fn wrap_text(text: &str, max_width: f32, font_size: u16) -> String {
     result
 }
 
-fn draw_snes_block(x: f32, y: f32, size: f32, color: Color) {
-    draw_rectangle(x, y, size, size, color);
+fn draw_snes_block(x: f32, y: f32, size: f32, color: Color, style: BlockStyle, outline: bool) {
     let highlight = Color::new(
         (color.r + 0.4).min(1.0),
         (color.g + 0.4).min(1.0),
@@ -910,13 +2865,48 @@ fn draw_snes_block(x: f32, y: f32, size: f32, color: Color) {
         1.0,
     );
     let border = size * 0.15;
-    draw_rectangle(x, y, size, border, highlight);
-    draw_rectangle(x, y, border, size, highlight);
-    draw_rectangle(x, y + size - border, size, border, shadow);
-    draw_rectangle(x + size - border, y, border, size, shadow);
+    match style {
+        BlockStyle::Bevel => {
+            draw_rectangle(x, y, size, size, color);
+            draw_rectangle(x, y, size, border, highlight);
+            draw_rectangle(x, y, border, size, highlight);
+            draw_rectangle(x, y + size - border, size, border, shadow);
+            draw_rectangle(x + size - border, y, border, size, shadow);
+        }
+        BlockStyle::Rounded => {
+            // Inset by at least a pixel so neighbouring blocks stay distinct
+            // even at the 15px stats preview size.
+            let inset = (size * 0.05).max(1.0);
+            let inner = size - inset * 2.0;
+            let radius = inner * 0.25;
+            // Shadow base, then the body nudged up-left so the shadow shows
+            // along the bottom/right edges, then a small highlight.
+            draw_rounded_rect(x + inset, y + inset, inner, inner, radius, shadow);
+            draw_rounded_rect(x + inset, y + inset, inner - border, inner - border, radius, color);
+            draw_circle(x + inset + radius, y + inset + radius, radius * 0.6, highlight);
+        }
+    }
+    // A 1px frame a shade below the shadow, kept inside the cell so it
+    // never paints over a neighbour.
+    if outline {
+        let edge = Color::new(shadow.r * 0.6, shadow.g * 0.6, shadow.b * 0.6, 1.0);
+        draw_rectangle(x, y, size, 1.0, edge);
+        draw_rectangle(x, y + size - 1.0, size, 1.0, edge);
+        draw_rectangle(x, y, 1.0, size, edge);
+        draw_rectangle(x + size - 1.0, y, 1.0, size, edge);
+    }
+}
+
+fn draw_rounded_rect(x: f32, y: f32, w: f32, h: f32, radius: f32, color: Color) {
+    draw_rectangle(x + radius, y, w - radius * 2.0, h, color);
+    draw_rectangle(x, y + radius, w, h - radius * 2.0, color);
+    draw_circle(x + radius, y + radius, radius, color);
+    draw_circle(x + w - radius, y + radius, radius, color);
+    draw_circle(x + radius, y + h - radius, radius, color);
+    draw_circle(x + w - radius, y + h - radius, radius, color);
 }
 
-fn draw_preview(tetromino: &Tetromino, pos_x: f32, pos_y: f32, tile_size: f32) {
+fn draw_preview(tetromino: &Tetromino, pos_x: f32, pos_y: f32, tile_size: f32, style: BlockStyle, outline: bool) {
     let mut min_x = i32::MAX;
     let mut min_y = i32::MAX;
     let mut max_x = i32::MIN;
@@ -934,7 +2924,7 @@ fn draw_preview(tetromino: &Tetromino, pos_x: f32, pos_y: f32, tile_size: f32) {
     for &[bx, by] in tetromino.shape.iter() {
         let draw_x = offset_x + (bx - min_x) as f32 * tile_size;
         let draw_y = offset_y + (by - min_y) as f32 * tile_size;
-        draw_snes_block(draw_x, draw_y, tile_size, tetromino.color);
+        draw_snes_block(draw_x, draw_y, tile_size, tetromino.color, style, outline);
     }
 }
 
@@ -943,13 +2933,766 @@ async fn main() {
     // Optionally, set the window size:
     request_new_screen_size(1410.0, 700.0);
     let mut game_state = GameState::new();
+    // Handle the window close ourselves so shutdown runs before exiting.
+    prevent_quit();
 
     loop {
-        if is_key_pressed(KeyCode::Space) && !game_state.started {
-            game_state.start_game();
+        if is_quit_requested() {
+            break;
+        }
+        if is_key_pressed(KeyCode::Space)
+            && !game_state.started
+            && game_state.calibration.is_none()
+            && !game_state.show_color_editor
+        {
+            if game_state.game_over_anim_timer > 0.0 {
+                // Skip the game-over curtain.
+                game_state.game_over_anim_timer = 0.0;
+            } else if game_state.game_over {
+                // Leave the frozen game-over board for the start screen.
+                game_state.game_over = false;
+            } else {
+                game_state.start_game();
+            }
         }
         game_state.update();
         game_state.draw();
         next_frame().await;
     }
+    game_state.shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKED: Cell = Some((GRAY, TetrominoType::O, 0));
+
+    // A game that never opens the audio device.
+    fn silent_game() -> GameState {
+        GameState::with_music(MusicManager::silent())
+    }
+
+    // Rows soft-dropped (and points scored) over `frames` frames of `dt`.
+    fn soft_drop_rows(dt: f32, frames: usize) -> (i32, u32) {
+        let mut game = silent_game();
+        game.start_game();
+        let start_y = game.tetromino.unwrap().pos.1;
+        for _ in 0..frames {
+            game.apply_gravity(dt, true);
+        }
+        (game.tetromino.unwrap().pos.1 - start_y, game.score)
+    }
+
+    #[test]
+    fn soft_drop_rate_ignores_frame_rate() {
+        // Both runs last 1.03125s, just over 15 soft-drop steps.
+        let slow = soft_drop_rows(1.0 / 32.0, 33);
+        let fast = soft_drop_rows(1.0 / 128.0, 132);
+        assert_eq!(slow, (15, 15));
+        assert_eq!(fast, slow);
+    }
+
+    #[test]
+    fn pausing_stops_the_play_clock() {
+        let mut game = silent_game();
+        game.start_game();
+        for _ in 0..60 {
+            game.advance_play_clock(1.0 / 60.0);
+        }
+        let played = game.effective_play_time();
+        assert!((played - 1.0).abs() < 1e-9);
+        game.set_paused(true);
+        for _ in 0..120 {
+            game.advance_play_clock(1.0 / 60.0);
+        }
+        assert_eq!(game.effective_play_time(), played);
+        game.set_paused(false);
+        game.advance_play_clock(1.0 / 60.0);
+        assert!(game.effective_play_time() > played);
+    }
+
+    #[test]
+    fn remove_rows_matches_the_old_rebuild() {
+        // Every row is distinct, so any misplaced row shows up.
+        let mut board = [[None; GRID_WIDTH]; GRID_HEIGHT];
+        for (y, row) in board.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                if (x + y) % 3 != 0 {
+                    *cell = Some((GRAY, TetrominoType::T, y as u32));
+                }
+            }
+        }
+        let cleared = [3, 7, 8, 15, 21];
+
+        // The Vec-based version clear_lines_delayed used before.
+        let mut rebuilt: Vec<[Cell; GRID_WIDTH]> = Vec::new();
+        for (i, row) in board.iter().enumerate() {
+            if cleared.contains(&i) { continue; }
+            rebuilt.push(*row);
+        }
+        while rebuilt.len() < GRID_HEIGHT {
+            rebuilt.insert(0, [None; GRID_WIDTH]);
+        }
+        let expected: [[Cell; GRID_WIDTH]; GRID_HEIGHT] = rebuilt.try_into().unwrap();
+
+        remove_rows(&mut board, &cleared);
+        assert_eq!(board, expected);
+    }
+
+    #[test]
+    fn cached_ghost_follows_the_piece() {
+        let mut game = silent_game();
+        game.start_game();
+        // A staircase, so every column lands at a different height.
+        for x in 0..GRID_WIDTH {
+            for y in GRID_HEIGHT - x..GRID_HEIGHT {
+                game.board[y][x] = LOCKED;
+            }
+        }
+        game.ghost_y = None;
+        for step in [(-1, 0), (-1, 0), (1, 0), (1, 0), (1, 0), (0, 1)] {
+            let before = game.ghost_y();
+            game.move_tetromino(step);
+            let cached = game.ghost_y();
+            game.ghost_y = None;
+            assert_eq!(cached, game.ghost_y());
+            // Sideways moves change the landing row, so a stale value would show.
+            if step.0 != 0 {
+                assert_ne!(before, cached);
+            }
+        }
+        let curr = game.tetromino.unwrap();
+        game.set_tetromino_shape(rotate_shape(&curr.shape, curr.t_type, true));
+        let cached = game.ghost_y();
+        game.ghost_y = None;
+        assert_eq!(cached, game.ghost_y());
+    }
+
+    #[test]
+    fn blocked_spawn_is_a_block_out() {
+        let mut game = silent_game();
+        game.start_game();
+        for row in game.board.iter_mut().take(HIDDEN_ROWS) {
+            row[GRID_WIDTH / 2] = LOCKED;
+            row[GRID_WIDTH / 2 - 1] = LOCKED;
+        }
+        game.spawn_new_tetromino();
+        assert!(game.game_over);
+        assert_eq!(game.top_out_reason, Some(TopOut::BlockOut));
+    }
+
+    // Locks the spawned piece where it stands, on a floor just below the
+    // hidden rows.
+    fn lock_in_hidden_rows(lock_out_enabled: bool) -> GameState {
+        let mut game = silent_game();
+        game.lock_out_enabled = lock_out_enabled;
+        game.start_game();
+        // Leave a gap so the floor itself never clears.
+        for x in 1..GRID_WIDTH {
+            game.board[HIDDEN_ROWS][x] = LOCKED;
+        }
+        game.lock_tetromino();
+        game
+    }
+
+    #[test]
+    fn locking_above_the_field_is_a_lock_out() {
+        let game = lock_in_hidden_rows(true);
+        assert!(game.game_over);
+        assert_eq!(game.top_out_reason, Some(TopOut::LockOut));
+    }
+
+    #[test]
+    fn lock_out_can_be_turned_off() {
+        // The next spawn still blocks out against the locked piece.
+        let game = lock_in_hidden_rows(false);
+        assert_eq!(game.top_out_reason, Some(TopOut::BlockOut));
+    }
+
+    #[test]
+    fn a_lock_out_ends_the_game_once() {
+        let mut game = lock_in_hidden_rows(true);
+        assert!(game.tetromino.is_none());
+        // Partway through the curtain, the rest of the frame runs gravity.
+        game.game_over_anim_timer = 0.5;
+        let next_id = game.next_piece_id;
+        game.apply_gravity(1.0, false);
+        game.top_out(TopOut::BlockOut);
+        game.end_game();
+        assert_eq!(game.game_over_anim_timer, 0.5);
+        assert_eq!(game.next_piece_id, next_id);
+        assert_eq!(game.top_out_reason, Some(TopOut::LockOut));
+    }
+
+    #[test]
+    fn music_pause_tracks_the_game() {
+        let mut game = silent_game();
+        game.start_game();
+        assert!(!game.mus_mgr.mus_sink.is_paused());
+        for _ in 0..5 {
+            game.set_paused(!game.paused);
+            assert_eq!(game.mus_mgr.mus_sink.is_paused(), game.paused);
+        }
+        // Restarting from a paused game brings the music back too.
+        assert!(game.paused);
+        game.start_game();
+        assert!(!game.paused);
+        assert!(!game.mus_mgr.mus_sink.is_paused());
+    }
+
+    // Drops the active piece straight down and locks it.
+    fn hard_drop(game: &mut GameState) {
+        let landing = game.ghost_y().unwrap();
+        let curr = game.tetromino.unwrap();
+        game.move_tetromino((0, landing - curr.pos.1));
+        game.lock_tetromino();
+    }
+
+    #[test]
+    fn hold_limit_stops_further_holds() {
+        let mut game = silent_game();
+        game.hold_limit = Some(2);
+        game.start_game();
+        for used in 1..=2 {
+            game.hold();
+            assert_eq!(game.holds_used, used);
+            hard_drop(&mut game);
+        }
+        assert_eq!(game.holds_left(), Some(0));
+        let active = game.tetromino.unwrap();
+        let held = game.hold_tetromino.unwrap();
+        game.hold();
+        assert_eq!(game.tetromino.unwrap().t_type, active.t_type);
+        assert_eq!(game.tetromino.unwrap().pos, active.pos);
+        assert_eq!(game.hold_tetromino.unwrap().t_type, held.t_type);
+        assert_eq!(game.holds_used, 2);
+    }
+
+    #[test]
+    fn hold_does_nothing_when_disabled() {
+        let mut game = silent_game();
+        game.hold_enabled = false;
+        game.start_game();
+        let active = game.tetromino.unwrap();
+        let next = game.next_queue[0].t_type;
+        game.hold();
+        assert!(game.hold_tetromino.is_none());
+        assert_eq!(game.tetromino.unwrap().t_type, active.t_type);
+        assert_eq!(game.next_queue[0].t_type, next);
+        assert_eq!(game.holds_used, 0);
+    }
+
+    #[test]
+    fn ultra_hold_costs_time() {
+        let mut game = silent_game();
+        game.mode = GameMode::Ultra;
+        game.hold_costs_time = true;
+        game.start_game();
+        game.advance_play_clock(10.0);
+        let before = game.ultra_time_left();
+        game.hold();
+        assert_eq!(game.ultra_time_left(), before - HOLD_TIME_COST);
+    }
+
+    #[test]
+    fn zen_shifts_a_topped_out_stack_down() {
+        let mut game = silent_game();
+        game.mode = GameMode::Zen;
+        game.start_game();
+        // Every row keeps one gap and remembers where it started.
+        for (y, row) in game.board.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                if x != y % GRID_WIDTH {
+                    *cell = Some((GRAY, TetrominoType::O, y as u32));
+                }
+            }
+        }
+        let original = game.board;
+        game.tetromino = None;
+        game.spawn_new_tetromino();
+
+        let curr = game.tetromino.expect("the next piece spawned");
+        assert!(!game.game_over);
+        assert!(!game.check_collision(&curr.shape, curr.pos));
+        let shift = game.board.iter().take_while(|row| row.iter().all(|c| c.is_none())).count();
+        assert!(shift > 0);
+        assert_eq!(game.board[shift..], original[..GRID_HEIGHT - shift]);
+    }
+
+    fn flat_i(game: &GameState) -> Tetromino {
+        game.new_piece(TetrominoType::I)
+    }
+
+    #[test]
+    fn optimal_inputs_for_known_placements() {
+        let game = silent_game();
+        let spawn = flat_i(&game);
+        let vertical = rotate_shape(&spawn.shape, TetrominoType::I, true);
+        // Dropped where it spawns.
+        assert_eq!(optimal_inputs(&spawn, &spawn.shape, spawn.pos.0), Some(0));
+        // One tap either way.
+        assert_eq!(optimal_inputs(&spawn, &spawn.shape, spawn.pos.0 - 1), Some(1));
+        // Holding into either wall is a single input.
+        assert_eq!(optimal_inputs(&spawn, &spawn.shape, 0), Some(1));
+        assert_eq!(optimal_inputs(&spawn, &spawn.shape, GRID_WIDTH as i32 - 4), Some(1));
+        // Rotate, then hold into the wall.
+        assert_eq!(optimal_inputs(&spawn, &vertical, -1), Some(2));
+    }
+
+    // Places a flat I against the left wall after `inputs` presses.
+    fn finesse_faults_after(inputs: u32) -> u32 {
+        let mut game = silent_game();
+        game.finesse_training = true;
+        game.start_game();
+        game.tetromino = Some(flat_i(&game));
+        let x = game.tetromino.unwrap().pos.0;
+        game.move_tetromino((-x, 0));
+        game.piece_inputs = inputs;
+        hard_drop(&mut game);
+        game.finesse_faults
+    }
+
+    #[test]
+    fn extra_inputs_are_finesse_faults() {
+        // Holding left into the wall is optimal; tap-tap-tap is not.
+        assert_eq!(finesse_faults_after(1), 0);
+        assert_eq!(finesse_faults_after(3), 1);
+    }
+
+    #[test]
+    fn a_clearing_lock_counts_one_fault() {
+        let mut game = silent_game();
+        game.finesse_training = true;
+        game.start_game();
+        for x in 4..GRID_WIDTH {
+            game.board[GRID_HEIGHT - 1][x] = LOCKED;
+        }
+        game.tetromino = Some(flat_i(&game));
+        let x = game.tetromino.unwrap().pos.0;
+        game.move_tetromino((-x, 0));
+        game.piece_inputs = 3;
+        hard_drop(&mut game);
+        assert!(!game.clearing_lines.is_empty());
+        // Gravity still runs for the rest of the frame the piece locked on.
+        game.apply_gravity(1.0, false);
+        assert_eq!(game.finesse_faults, 1);
+    }
+
+    #[test]
+    fn rotation_blocked_at_the_top_does_nothing() {
+        let mut game = silent_game();
+        game.start_game();
+        game.tetromino = Some(flat_i(&game));
+        // Either vertical I would poke above the board at spawn.
+        assert!(!game.rotate_tetromino(true));
+        assert!(!game.rotate_tetromino(false));
+        assert_eq!(game.tetromino.unwrap().shape, flat_i(&game).shape);
+        // Two rows further down it turns.
+        game.move_tetromino((0, 2));
+        assert!(game.rotate_tetromino(true));
+    }
+
+    // The tail of update for one frame without any keys held.
+    fn idle_frame(game: &mut GameState, dt: f32) {
+        if !game.square_freeze(dt) {
+            game.apply_gravity(dt, false);
+            game.update_square_effects(dt);
+        }
+    }
+
+    #[test]
+    fn nothing_falls_while_a_square_blinks() {
+        let mut game = silent_game();
+        game.start_game();
+        for row in &mut game.board[GRID_HEIGHT - 4..] {
+            for cell in &mut row[..4] {
+                *cell = Some((GRAY, TetrominoType::O, 99));
+            }
+        }
+        game.check_for_4x4_squares();
+        assert_eq!(game.active_squares.len(), 1);
+
+        let start = game.tetromino.unwrap().pos;
+        for _ in 0..120 {
+            idle_frame(&mut game, 1.0 / 60.0);
+            assert_eq!(game.tetromino.unwrap().pos, start);
+        }
+        // Once the blink finishes, gravity picks up again.
+        for _ in 0..300 {
+            idle_frame(&mut game, 1.0 / 60.0);
+        }
+        assert!(game.active_squares.is_empty());
+        assert!(game.tetromino.unwrap().pos.1 > start.1);
+    }
+
+    #[test]
+    fn rookie_assist_waits_for_input() {
+        let mut game = silent_game();
+        game.rookie_assist = true;
+        game.start_game();
+        let start = game.tetromino.unwrap().pos;
+        // Well past several gravity steps, but inside the wait timeout.
+        for _ in 0..120 {
+            game.apply_gravity(1.0 / 60.0, false);
+        }
+        assert_eq!(game.tetromino.unwrap().pos, start);
+        // Any move or rotation clears the wait in process_input.
+        game.waiting_for_input = false;
+        game.apply_gravity(1.0, false);
+        assert!(game.tetromino.unwrap().pos.1 > start.1);
+    }
+
+    #[test]
+    fn rookie_wait_times_out() {
+        let mut game = silent_game();
+        game.rookie_assist = true;
+        game.start_game();
+        let start = game.tetromino.unwrap().pos;
+        game.apply_gravity(ROOKIE_WAIT_TIMEOUT, false);
+        assert!(!game.waiting_for_input);
+        game.apply_gravity(1.0, false);
+        assert!(game.tetromino.unwrap().pos.1 > start.1);
+    }
+
+    #[test]
+    fn game_log_writes_one_header_and_a_row_per_game() {
+        let path = std::env::temp_dir().join(format!("rust_tetris_games_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut game = silent_game();
+        game.start_game();
+        game.score = 1200;
+        game.append_game_log(path).unwrap();
+        game.mode = GameMode::Zen;
+        game.start_game();
+        game.score = 340;
+        game.append_game_log(path).unwrap();
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], game_log_header());
+        let columns = lines[0].split(',').count();
+        assert_eq!(columns, 5 + TETROMINOES.len() + PENTOMINOES.len());
+        for (line, (mode, score)) in lines[1..].iter().zip([("Classic", "1200"), ("Zen", "340")]) {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields.len(), columns);
+            assert_eq!(fields[1], mode);
+            assert_eq!(fields[2], score);
+        }
+    }
+
+    #[test]
+    fn second_chance_saves_only_the_first_top_out() {
+        let mut game = silent_game();
+        game.second_chance_assist = true;
+        game.start_game();
+        game.score = SECOND_CHANCE_PENALTY + 500;
+        for round in 0..2 {
+            for row in game.board.iter_mut().take(HIDDEN_ROWS) {
+                row[GRID_WIDTH / 2] = LOCKED;
+                row[GRID_WIDTH / 2 - 1] = LOCKED;
+            }
+            game.spawn_new_tetromino();
+            if round == 0 {
+                assert!(!game.game_over);
+                assert!(game.tetromino.is_some());
+                assert_eq!(game.score, 500);
+                assert_eq!(game.second_chances_remaining, 0);
+            }
+        }
+        assert!(game.game_over);
+        assert_eq!(game.top_out_reason, Some(TopOut::BlockOut));
+    }
+
+    #[test]
+    fn bag_deals_each_piece_once_per_round() {
+        for set in [&TETROMINOES[..], &PENTOMINOES[..]] {
+            let mut piece_gen = PieceGenerator::new(Randomizer::SevenBag);
+            for _ in 0..50 {
+                let round: Vec<_> = (0..set.len()).map(|_| piece_gen.next_piece(set)).collect();
+                for piece in set {
+                    assert_eq!(round.iter().filter(|&p| p == piece).count(), 1);
+                }
+            }
+        }
+    }
+
+    // Fraction of draws that repeat one of the previous TGM_HISTORY_LEN.
+    fn recent_repeat_rate(kind: Randomizer) -> f64 {
+        const DRAWS: usize = 10_000;
+        let mut piece_gen = PieceGenerator::new(kind);
+        let mut recent = VecDeque::new();
+        let mut repeats = 0;
+        for _ in 0..DRAWS {
+            let piece = piece_gen.next_piece(&TETROMINOES);
+            if recent.contains(&piece) {
+                repeats += 1;
+            }
+            if recent.len() == TGM_HISTORY_LEN {
+                recent.pop_front();
+            }
+            recent.push_back(piece);
+        }
+        repeats as f64 / DRAWS as f64
+    }
+
+    #[test]
+    fn tgm_history_rarely_repeats_recent_pieces() {
+        // True random repeats roughly half the time; TGM's rerolls bring
+        // that down to a few percent.
+        let random = recent_repeat_rate(Randomizer::TrueRandom);
+        let tgm = recent_repeat_rate(Randomizer::TgmHistory);
+        assert!(tgm < 0.15, "TGM repeated {:.1}% of draws", tgm * 100.0);
+        assert!(tgm * 4.0 < random, "TGM {:.3} vs true random {:.3}", tgm, random);
+    }
+
+    fn sorted_cells(shape: &Shape) -> Vec<[i32; 2]> {
+        let mut cells = shape.to_vec();
+        cells.sort();
+        cells
+    }
+
+    // Turns a pentomino through all four states and back.
+    fn assert_rotations(t_type: TetrominoType, states: [[[i32; 2]; 5]; 4]) {
+        let mut shape = TETROMINO_SHAPES[t_type as usize];
+        for state in states.iter().cycle().skip(1).take(4) {
+            shape = rotate_shape(&shape, t_type, true);
+            assert_eq!(sorted_cells(&shape), sorted_cells(&Shape::pentomino(*state)), "{:?}", t_type);
+        }
+        let back = rotate_shape(&TETROMINO_SHAPES[t_type as usize], t_type, false);
+        assert_eq!(sorted_cells(&back), sorted_cells(&Shape::pentomino(states[3])), "{:?}", t_type);
+    }
+
+    #[test]
+    fn pentominoes_rotate_about_their_pivot() {
+        assert_rotations(TetrominoType::F5, [
+            [[1,0],[2,0],[0,1],[1,1],[1,2]],
+            [[0,0],[0,1],[1,1],[2,1],[1,2]],
+            [[1,0],[1,1],[2,1],[0,2],[1,2]],
+            [[1,0],[0,1],[1,1],[2,1],[2,2]],
+        ]);
+        // I5 turns about its middle cell, so it stays in place when flat.
+        assert_rotations(TetrominoType::I5, [
+            [[0,0],[1,0],[2,0],[3,0],[4,0]],
+            [[2,-2],[2,-1],[2,0],[2,1],[2,2]],
+            [[0,0],[1,0],[2,0],[3,0],[4,0]],
+            [[2,-2],[2,-1],[2,0],[2,1],[2,2]],
+        ]);
+    }
+
+    #[test]
+    fn pentominoes_collide_on_all_five_cells() {
+        let mut game = silent_game();
+        let shape = TETROMINO_SHAPES[TetrominoType::F5 as usize];
+        assert!(!game.check_collision(&shape, (0, 0)));
+        assert!(game.check_collision(&shape, (-1, 0)));
+        assert!(!game.check_collision(&shape, (GRID_WIDTH as i32 - 3, 0)));
+        assert!(game.check_collision(&shape, (GRID_WIDTH as i32 - 2, 0)));
+        assert!(!game.check_collision(&shape, (0, GRID_HEIGHT as i32 - 3)));
+        assert!(game.check_collision(&shape, (0, GRID_HEIGHT as i32 - 2)));
+        // Only the fifth cell, the F's stem, reaches this one.
+        game.board[10][5] = LOCKED;
+        assert!(game.check_collision(&shape, (4, 8)));
+        assert!(!game.check_collision(&shape, (4, 7)));
+    }
+
+    #[test]
+    fn a_locked_pentomino_fills_five_cells() {
+        let mut game = silent_game();
+        game.mode = GameMode::Pento;
+        game.start_game();
+        game.tetromino = Some(game.new_piece(TetrominoType::F5));
+        hard_drop(&mut game);
+        let filled = game.board.iter().flatten().filter(|cell| cell.is_some()).count();
+        assert_eq!(filled, 5);
+    }
+
+    fn big_game() -> GameState {
+        let mut game = silent_game();
+        game.big_mode = true;
+        game.start_game();
+        game.tetromino = Some(flat_i(&game));
+        game
+    }
+
+    #[test]
+    fn big_mode_collides_on_the_coarse_grid() {
+        let game = big_game();
+        let shape = flat_i(&game).shape;
+        // Four coarse cells leave one spare coarse column on a 5-wide board.
+        assert!(!game.check_collision(&shape, (0, 5)));
+        assert!(!game.check_collision(&shape, (1, 5)));
+        assert!(game.check_collision(&shape, (2, 5)));
+        assert!(game.check_collision(&shape, (-1, 5)));
+
+        // Any one board cell under a coarse block is enough to block it.
+        let mut game = big_game();
+        game.board[11][9] = LOCKED;
+        assert!(game.check_collision(&shape, (1, 5)));
+        assert!(!game.check_collision(&shape, (1, 6)));
+    }
+
+    #[test]
+    fn big_mode_clears_two_board_rows_per_coarse_row() {
+        let mut game = big_game();
+        // The bottom coarse row is full apart from the I's landing spot,
+        // with a marker block sitting on top of it.
+        for row in &mut game.board[GRID_HEIGHT - 2..] {
+            for cell in &mut row[8..] {
+                *cell = LOCKED;
+            }
+        }
+        game.board[GRID_HEIGHT - 3][9] = LOCKED;
+        hard_drop(&mut game);
+        assert_eq!(game.clearing_lines, vec![GRID_HEIGHT - 2, GRID_HEIGHT - 1]);
+
+        game.clear_lines_delayed();
+        assert_eq!(game.lines_cleared, 2);
+        // The marker falls by a whole coarse row.
+        assert!(game.board[GRID_HEIGHT - 1][9].is_some());
+        assert_eq!(game.board[GRID_HEIGHT - 1].iter().filter(|c| c.is_some()).count(), 1);
+    }
+
+    #[test]
+    fn pieces_spawn_with_their_configured_rotation() {
+        let mut game = silent_game();
+        game.spawn_rotations[TetrominoType::I as usize] = 1;
+        let piece = game.new_piece(TetrominoType::I);
+        let vertical = rotate_shape(&TETROMINO_SHAPES[TetrominoType::I as usize], TetrominoType::I, true);
+        assert_eq!(piece.shape, vertical);
+        // Still entering on the top row, inside the board.
+        let top = game.piece_cells(&piece.shape, piece.pos).iter().map(|&(_, y)| y).min();
+        assert_eq!(top, Some(0));
+        assert!(!game.check_collision(&piece.shape, piece.pos));
+        // Types left at their default keep the usual orientation.
+        let t = game.new_piece(TetrominoType::T);
+        assert_eq!(t.shape, TETROMINO_SHAPES[TetrominoType::T as usize]);
+
+        // Every piece a game deals uses the setting.
+        for t_type in TETROMINOES {
+            game.spawn_rotations[t_type as usize] = 2;
+        }
+        game.start_game();
+        for piece in game.tetromino.iter().chain(game.next_queue.iter()) {
+            let mut expected = TETROMINO_SHAPES[piece.t_type as usize];
+            for _ in 0..2 {
+                expected = rotate_shape(&expected, piece.t_type, true);
+            }
+            assert_eq!(piece.shape, expected);
+        }
+    }
+
+    #[test]
+    fn settings_overlay_restores_the_pause_state() {
+        let mut game = silent_game();
+        game.start_game();
+        game.toggle_settings_overlay();
+        assert!(game.paused);
+        game.toggle_settings_overlay();
+        assert!(!game.paused);
+
+        // Paused by the player first: closing the overlay keeps it paused.
+        game.set_paused(true);
+        game.toggle_settings_overlay();
+        game.toggle_settings_overlay();
+        assert!(game.paused);
+        assert!(!game.show_settings_overlay);
+    }
+
+    #[test]
+    fn game_over_closes_the_help_overlay() {
+        let mut game = silent_game();
+        game.start_game();
+        game.show_controls_overlay = true;
+        game.end_game();
+        assert!(!game.show_controls_overlay);
+    }
+
+    #[test]
+    fn settings_survive_a_save_and_load() {
+        let mut game = silent_game();
+        // Move every setting off its default.
+        for item in MenuItem::ALL {
+            game.adjust_setting(item, 1);
+        }
+        game.spawn_rotations[TetrominoType::S as usize] = 3;
+        game.layout = Layout::Compact;
+        game.adjust_setting(MenuItem::ColorGreen, -1);
+        let text = game.settings_text();
+
+        let mut loaded = silent_game();
+        assert_ne!(loaded.settings_text(), text);
+        loaded.apply_settings_text(&text);
+        assert_eq!(loaded.settings_text(), text);
+    }
+
+    #[test]
+    fn bad_settings_lines_are_skipped() {
+        let mut game = silent_game();
+        game.apply_settings_text("hold=maybe\nno equals sign\nnext_pieces=9\ncolour=teal\nmode=Zen\n");
+        assert!(game.hold_enabled);
+        assert_eq!(game.next_preview_count, NEXT_QUEUE_LEN);
+        assert_eq!(game.mode, GameMode::Zen);
+    }
+
+    #[test]
+    fn closing_the_window_ends_the_game() {
+        let mut game = silent_game();
+        game.start_game();
+        game.shutdown();
+        assert!(game.game_over);
+        // Nothing to end from the start screen.
+        let mut idle = silent_game();
+        idle.shutdown();
+        assert!(!idle.game_over);
+    }
+
+    #[test]
+    fn piece_colors_override_the_nes_palette() {
+        let mut game = silent_game();
+        game.color_edit_index = TETROMINOES.iter().position(|&t| t == TetrominoType::S).unwrap();
+        game.adjust_setting(MenuItem::ColorRed, 1);
+        game.adjust_setting(MenuItem::ColorBlue, -1);
+        let nes = NES_COLORS[TetrominoType::S as usize];
+        let custom = game.new_piece(TetrominoType::S).color;
+        assert!((custom.r - (nes.r + COLOR_CHANNEL_STEP).min(1.0)).abs() < 1e-6);
+        assert!((custom.b - (nes.b - COLOR_CHANNEL_STEP).max(0.0)).abs() < 1e-6);
+        assert_eq!(game.new_piece(TetrominoType::Z).color, NES_COLORS[TetrominoType::Z as usize]);
+
+        game.adjust_setting(MenuItem::ResetColors, 1);
+        assert_eq!(game.new_piece(TetrominoType::S).color, nes);
+    }
+
+    #[test]
+    fn piece_color_screen_edits_only_tetrominoes() {
+        let mut game = silent_game();
+        for _ in 0..TETROMINOES.len() {
+            game.adjust_setting(MenuItem::ColorRed, -10);
+            game.adjust_setting(MenuItem::ColorPiece, 1);
+        }
+        assert_eq!(game.color_edit_index, 0);
+        assert!(TETROMINOES.iter().all(|&t| game.piece_color(t).r == 0.0));
+        assert_eq!(game.piece_color(TetrominoType::F5), NES_COLORS[TetrominoType::F5 as usize]);
+    }
+
+    #[test]
+    fn zen_survives_a_full_board() {
+        let mut game = silent_game();
+        game.mode = GameMode::Zen;
+        game.started = true;
+        for _ in 0..NEXT_QUEUE_LEN {
+            let piece = game.new_piece(TetrominoType::T);
+            game.next_queue.push_back(piece);
+        }
+        for _ in 0..20 {
+            game.board = [[LOCKED; GRID_WIDTH]; GRID_HEIGHT];
+            game.spawn_new_tetromino();
+            assert!(!game.game_over);
+            assert!(game.tetromino.is_some());
+        }
+    }
 }